@@ -0,0 +1,86 @@
+#![allow(non_snake_case)]
+
+#[macro_use]
+extern crate criterion;
+
+use criterion::Criterion;
+
+use privacypass_rs::{client, ecc, hashes, random, server};
+use privacypass_rs::types::curve::{big::BIG, ecp::ECP};
+
+const BATCH_SIZE: usize = 100;
+
+fn bench_fixed_base(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let G = hashes::hash_to_curve(b"bench generator").unwrap();
+    let table = ecc::FixedBaseTable::new(&G);
+    let k = random::rand_scalar_from_rng(&mut rng);
+
+    c.bench_function("fixed base mul/plain", move |b| b.iter(|| G.mul(&k)));
+    c.bench_function("fixed base mul/table", move |b| b.iter(|| table.mul(&k)));
+    c.bench_function("fixed base table/build", move |b| b.iter(|| ecc::FixedBaseTable::new(&G)));
+}
+
+// a 100 token issuance: the server's proof, and the client's check of it with and without tables
+fn bench_batch(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let x = random::rand_scalar_from_rng(&mut rng);
+    let G = hashes::hash_to_curve(b"bench generator").unwrap();
+    let Y = G.mul(&x);
+    let verifier = client::DleqVerifier::new(&G, &Y);
+
+    let mut tokens = vec![];
+    let mut Ms = vec![];
+    let mut Zs = vec![];
+    for _ in 0..BATCH_SIZE {
+        let token = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+        Ms.push(token.2);
+        Zs.push(server::sign_blinded_token(&x, &token.2));
+        tokens.push(token);
+    }
+    let (proof_c, proof_s) = server::batch_dleq(&x, &Zs, &Ms, &verifier, &mut rng);
+
+    let (plain_tokens, plain_Zs) = (tokens.clone(), Zs.clone());
+    c.bench_function("verify 100 tokens/plain", move |b| b.iter(|| {
+        client::process_issue_response(&plain_tokens, &plain_Zs, &G, &Y, &proof_s, &proof_c).unwrap()
+    }));
+
+    let (table_tokens, table_Zs) = (tokens.clone(), Zs.clone());
+    let table_verifier = client::DleqVerifier::new(&G, &Y);
+    c.bench_function("verify 100 tokens/tables", move |b| b.iter(|| {
+        table_verifier.process_issue_response(&table_tokens, &table_Zs, &proof_s, &proof_c).unwrap()
+    }));
+
+    c.bench_function("prove 100 tokens/tables", move |b| {
+        let mut rng = rand::thread_rng();
+        b.iter(|| server::batch_dleq(&x, &Zs, &Ms, &verifier, &mut rng))
+    });
+}
+
+// the composites M and Z of a batch proof, one mul and add per token against a multi-scalar mul
+fn bench_composites(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let sizes : Vec<usize> = vec![10, 100, 1000];
+    let points : Vec<ECP> = (0..1000u32)
+        .map(|i| hashes::hash_to_curve(&i.to_be_bytes()).unwrap())
+        .collect();
+    let scalars : Vec<BIG> = (0..1000)
+        .map(|_| random::rand_scalar_from_rng(&mut rng))
+        .collect();
+
+    let (naive_points, naive_scalars) = (points.clone(), scalars.clone());
+    c.bench_function_over_inputs("composite/naive", move |b, &n| b.iter(|| {
+        let mut M = ECP::new();
+        for (P, e) in naive_points[..n].iter().zip(naive_scalars[..n].iter()) {
+            M.add(&P.mul(e));
+        }
+        M
+    }), sizes.clone());
+
+    c.bench_function_over_inputs("composite/multi mul", move |b, &n| b.iter(|| {
+        ecc::multi_mul(&points[..n], &scalars[..n])
+    }), sizes);
+}
+
+criterion_group!(benches, bench_fixed_base, bench_batch, bench_composites);
+criterion_main!(benches);
@@ -5,54 +5,490 @@ extern crate privacypass_rs;
 
 use privacypass_rs::server::*;
 use privacypass_rs::db;
+use privacypass_rs::net;
+use privacypass_rs::http;
+use privacypass_rs::{converters, ecc, commitment, token, types};
+use privacypass_rs::group::Group;
+use privacypass_rs::secret::{SecretScalar, Zeroizing};
 
 use std::net::{TcpStream, TcpListener};
-use std::io::{Read, Write};
-use std::fs;
+use std::io::{self, Read, Write};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
 use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use std::collections::HashMap;
 use rand::Rng;
+use threadpool::ThreadPool;
 
-fn handle_client<R: Rng>(stream: &mut TcpStream, processor: &mut ServerProcessor, rng: &mut R) -> Result<(), Box<Error>> {
-    let mut buf = vec![0; 10*1024*1024];
+fn handle_client<R: Rng>(stream: &mut TcpStream, processor: &ServerProcessor, framing: net::Framing, rng: &mut R) -> Result<(), Box<Error>> {
     loop {
-        let n = stream.read(&mut buf)?;
-        let msg = &buf[..n];
+        let msg = match framing {
+            net::Framing::LengthPrefixed => net::read_frame(stream)?,
+            net::Framing::Legacy => net::read_legacy_request(stream)?,
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
         let response_str = processor.process_server_message(&msg, rng)?;
         debug!("response_str: {}", response_str);
-        stream.write(&response_str.as_bytes())?;
-        stream.flush()?;
+        match framing {
+            net::Framing::LengthPrefixed => net::write_frame(stream, &response_str.as_bytes())?,
+            net::Framing::Legacy => {
+                // legacy clients read the response until the connection is closed
+                stream.write_all(&response_str.as_bytes())?;
+                stream.flush()?;
+                return Ok(());
+            },
+        }
+    }
+}
+
+fn error_status(e: &privacypass_rs::Error) -> u16 {
+    match e {
+        privacypass_rs::Error::DoubleSpend | privacypass_rs::Error::InvalidMac | privacypass_rs::Error::InvalidToken => 403,
+        privacypass_rs::Error::NoIssuingKey => 404,
+        privacypass_rs::Error::Storage(_) | privacypass_rs::Error::Io(_) => 500,
+        _ => 400,
+    }
+}
+
+fn request_header(request: &tiny_http::Request, name: &'static str) -> Option<String> {
+    request.headers().iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+fn respond(request: tiny_http::Request, status: u16, body: Vec<u8>, content_type: &str) -> Result<(), Box<Error>> {
+    let content_type = tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .map_err(|_| "invalid content type")?;
+    request.respond(tiny_http::Response::from_data(body).with_header(content_type).with_status_code(status))?;
+    Ok(())
+}
+
+const ISSUER_DIRECTORY_PATH: &str = "/.well-known/private-token-issuer-directory";
+const TOKEN_REQUEST_PATH: &str = "/token-request";
+
+fn issuer_directory(processor: &ServerProcessor) -> Result<Vec<u8>, Box<Error>> {
+    let mut token_keys = vec![];
+    if let Some(ref key) = processor.token_key {
+        token_keys.push(serde_json::json!({
+            "token-type": token::TOKEN_TYPE_VOPRF,
+            "token-key": base64::encode_config(&token::VoprfGroup::serialize_element(&key.public_key), base64::URL_SAFE_NO_PAD),
+        }));
+    }
+    if let Some(ref key) = processor.public_token_key {
+        token_keys.push(serde_json::json!({
+            "token-type": token::TOKEN_TYPE_BLIND_RSA,
+            "token-key": base64::encode_config(&key.public_key_spki, base64::URL_SAFE_NO_PAD),
+        }));
+    }
+    if token_keys.is_empty() {
+        return Err("token issuance is not configured.".into());
+    }
+    let directory = serde_json::json!({
+        "issuer-request-uri": TOKEN_REQUEST_PATH,
+        "token-keys": token_keys,
+    });
+    Ok(serde_json::to_vec(&directory)?)
+}
+
+// RFC 9578 issuance and redemption, next to the extension's header redemption
+fn handle_http_request(mut request: tiny_http::Request, processor: &ServerProcessor) -> Result<(), Box<Error>> {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+
+    if path == ISSUER_DIRECTORY_PATH {
+        return match issuer_directory(processor) {
+            Ok(directory) => respond(request, 200, directory, "application/private-token-issuer-directory"),
+            Err(e) => respond(request, 404, e.to_string().into_bytes(), "text/plain"),
+        };
+    }
+
+    let content_type = request_header(&request, "Content-Type").unwrap_or_default();
+    if *request.method() == tiny_http::Method::Post && content_type == token::TOKEN_REQUEST_CONTENT_TYPE {
+        let mut body = vec![];
+        request.as_reader().take(net::MAX_MESSAGE_SIZE as u64).read_to_end(&mut body)?;
+        let result = processor.process_token_request(&body, &mut rand::thread_rng());
+        return match result {
+            Ok(response) => respond(request, 200, response, token::TOKEN_RESPONSE_CONTENT_TYPE),
+            Err(e) => respond(request, error_status(&e), e.to_string().into_bytes(), "text/plain"),
+        };
+    }
+
+    if let Some(authorization) = request_header(&request, "Authorization") {
+        let result = token::parse_authorization_header(&authorization)
+            .and_then(|token| processor.redeem_token(&token));
+        debug!("token redemption for {}: {:?}", path, result);
+        return match result {
+            Ok(()) => respond(request, 200, b"success".to_vec(), "text/plain"),
+            Err(e) => respond(request, error_status(&e), e.to_string().into_bytes(), "text/plain"),
+        };
+    }
+
+    let header = |name: &'static str| request_header(&request, name);
+    let token = header(http::TOKEN_HEADER);
+    // the token is bound to the host and path it's sent to, so both come from the request itself
+    let host = header("Host")
+        .map(|h| h.split(':').next().unwrap_or("").to_string())
+        .unwrap_or_default();
+
+    let (status, body, redeem_status) = match token {
+        None => (400, format!("missing {} header", http::TOKEN_HEADER), None),
+        Some(token) => match processor.process_redeem_header(&token, &host, &path) {
+            Ok(response_str) => (200, response_str, Some(types::RedeemStatus::Success)),
+            Err(e) => (error_status(&e), e.to_string(), redeem_status(&e)),
+        },
+    };
+    debug!("http redemption for {}{}: {} {}", host, path, status, body);
+
+    let content_type = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..])
+        .map_err(|_| "invalid content type")?;
+    let mut response = tiny_http::Response::from_data(body.into_bytes()).with_header(content_type).with_status_code(status);
+    if let Some(redeem_status) = redeem_status {
+        let status_header = tiny_http::Header::from_bytes(http::STATUS_HEADER.as_bytes(), redeem_status.name().as_bytes())
+            .map_err(|_| "invalid status header")?;
+        response = response.with_header(status_header);
+    }
+    request.respond(response)?;
+    Ok(())
+}
+
+fn run_http_server(server: tiny_http::Server, processor: Arc<ServerProcessor>, pool: ThreadPool, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::SeqCst) {
+        let request = match server.recv_timeout(Duration::from_millis(100)) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("error receiving http request: {}", e);
+                continue;
+            },
+        };
+
+        let processor = processor.clone();
+        pool.execute(move || {
+            if let Err(e) = handle_http_request(request, &processor) {
+                println!("error responding to http request: {}", e);
+            }
+        });
+    }
+}
+
+// the file contents and the returned bytes are wiped once dropped
+fn load_secret_key(path: &str) -> Result<Zeroizing<Vec<u8>>, Box<Error>> {
+    let contents = Zeroizing::new(fs::read(path)?);
+
+    let secret_key_pem = openssl::pkey::PKey::private_key_from_pem(&contents)?;
+    let secret_key_bytes = Zeroizing::new(secret_key_pem.ec_key()?.private_key().to_vec());
+
+    Ok(secret_key_bytes)
+}
+
+// commitment files may hold several versions, so pick the one made for this key
+fn load_issuer_key(secret_key_path: &str, commitment_path: &str) -> Result<IssuerKey, Box<Error>> {
+    let secret_key_bytes = load_secret_key(secret_key_path)?;
+    for (g_bytes, h_bytes, h2c) in commitment::load_all_commitments(commitment_path)? {
+        match IssuerKey::new(&secret_key_bytes, &g_bytes, &h_bytes, h2c) {
+            Ok(key) => return Ok(key),
+            Err(privacypass_rs::Error::CommitmentMismatch) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(privacypass_rs::Error::CommitmentMismatch.into())
+}
+
+// refuses to overwrite existing files so a key in use can't be lost by accident
+fn write_new_file(path: &str, contents: &[u8]) -> Result<(), Box<Error>> {
+    let mut f = OpenOptions::new().write(true).create_new(true).open(path)?;
+    f.write_all(contents)?;
+    Ok(())
+}
+
+#[allow(non_snake_case)]
+fn run_keygen(secret_key_path: &str, commitment_path: &str, settings: Option<&ServerSettings>) -> Result<(), Box<Error>> {
+    env_logger::try_init()?;
+
+    let group = openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1)?;
+    let secret_key = openssl::ec::EcKey::generate(&group)?;
+    let x = SecretScalar::new(converters::big_from_bytes(&Zeroizing::new(secret_key.private_key().to_vec())));
+
+    let mut rng = rand::thread_rng();
+    let (G, H) = generate_commitment(&x, &mut rng)?;
+
+    let h2c = settings.map(|s| s.h2c_method).unwrap_or_default();
+    let signing_key_path = settings.and_then(|s| s.commitment_signing_key_path.as_ref());
+    let commitment_bytes = match (settings, signing_key_path) {
+        (Some(settings), Some(signing_key_path)) => {
+            // signed commitments are added as a new version to the registry
+            let signing_key = openssl::pkey::PKey::private_key_from_pem(&fs::read(signing_key_path)?)?;
+            let expiry = commitment::now() + settings.commitment_validity_secs;
+            let entry = commitment::sign_entry(&settings.commitment_version, &G, &H, h2c, expiry, &signing_key)?;
+
+            let mut registry = if Path::new(commitment_path).exists() {
+                commitment::load_registry(commitment_path)?
+            } else {
+                commitment::CommitmentRegistry::new()
+            };
+            if registry.contains_key(&settings.commitment_version) {
+                return Err(format!("commitment version {} already exists in {}.", settings.commitment_version, commitment_path).into());
+            }
+            registry.insert(settings.commitment_version.clone(), entry);
+            serde_json::to_vec_pretty(&registry)?
+        },
+        _ => {
+            let mut commitment_struct : HashMap<String, String> = HashMap::new();
+            commitment_struct.insert("G".to_string(), base64::encode(&ecc::ecp_to_bytes(&G, false)));
+            commitment_struct.insert("H".to_string(), base64::encode(&ecc::ecp_to_bytes(&H, false)));
+            if !h2c.is_increment() {
+                commitment_struct.insert("h2c".to_string(), h2c.name().to_string());
+            }
+            if Path::new(commitment_path).exists() {
+                return Err(format!("{} already exists.", commitment_path).into());
+            }
+            serde_json::to_vec(&commitment_struct)?
+        },
+    };
+
+    write_new_file(secret_key_path, &secret_key.private_key_to_pem()?)?;
+    fs::write(commitment_path, &commitment_bytes)?;
+    println!("wrote secret key to {} and commitment to {}.", secret_key_path, commitment_path);
+
+    Ok(())
+}
+
+fn run_token_keygen(token_secret_key_path: &str) -> Result<(), Box<Error>> {
+    let group = openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::SECP384R1)?;
+    let secret_key = openssl::ec::EcKey::generate(&group)?;
+    let key = TokenIssuerKey::new(&Zeroizing::new(secret_key.private_key().to_vec()))?;
+
+    write_new_file(token_secret_key_path, &secret_key.private_key_to_pem()?)?;
+    println!("wrote token issuer key {} to {}.", key.id, token_secret_key_path);
+
+    Ok(())
+}
+
+fn load_token_key(settings: &ServerSettings) -> Result<Option<TokenIssuerKey>, Box<Error>> {
+    match settings.token_secret_key_path {
+        Some(ref path) => {
+            // keys made before type 0x0001 moved to P-384 would otherwise fail with a bare scalar error
+            let contents = Zeroizing::new(fs::read(path)?);
+            let secret_key = openssl::pkey::PKey::private_key_from_pem(&contents)?.ec_key()?;
+            if secret_key.group().curve_name() != Some(openssl::nid::Nid::SECP384R1) {
+                return Err(format!("{} is not a P-384 key, generate one with token-keygen.", path).into());
+            }
+            let key = TokenIssuerKey::new(&Zeroizing::new(secret_key.private_key().to_vec()))?;
+            println!("loaded token issuer key {}", key.id);
+            Ok(Some(key))
+        },
+        None => Ok(None),
+    }
+}
+
+fn run_public_token_keygen(public_token_secret_key_path: &str) -> Result<(), Box<Error>> {
+    let secret_key = openssl::rsa::Rsa::generate(8 * token::BLIND_RSA_KEY_SIZE as u32)?;
+    write_new_file(public_token_secret_key_path, &secret_key.private_key_to_pem()?)?;
+    let key = PublicTokenIssuerKey::new(secret_key)?;
+    println!("wrote public token issuer key {} to {}.", key.id, public_token_secret_key_path);
+
+    Ok(())
+}
+
+fn load_public_token_key(settings: &ServerSettings) -> Result<Option<PublicTokenIssuerKey>, Box<Error>> {
+    match settings.public_token_secret_key_path {
+        Some(ref path) => {
+            let secret_key = openssl::rsa::Rsa::private_key_from_pem(&fs::read(path)?)?;
+            let key = PublicTokenIssuerKey::new(secret_key)?;
+            println!("loaded public token issuer key {}", key.id);
+            Ok(Some(key))
+        },
+        None => Ok(None),
+    }
+}
+
+fn run_signing_keygen(signing_key_path: &str, verify_key_path: &str) -> Result<(), Box<Error>> {
+    let group = openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1)?;
+    let signing_key = openssl::ec::EcKey::generate(&group)?;
+    let verify_key = openssl::ec::EcKey::from_public_key(&group, signing_key.public_key())?;
+
+    write_new_file(signing_key_path, &signing_key.private_key_to_pem()?)?;
+    write_new_file(verify_key_path, &verify_key.public_key_to_pem()?)?;
+    println!("wrote commitment signing key to {} and verification key to {}.", signing_key_path, verify_key_path);
+
+    Ok(())
+}
+
+fn load_issuer_keys(settings: &ServerSettings) -> Result<Vec<IssuerKey>, Box<Error>> {
+    let mut keys = vec![load_issuer_key(&settings.secret_key_path, &settings.commitment_path)?];
+    for key_settings in settings.redeem_only_keys.iter() {
+        keys.push(load_issuer_key(&key_settings.secret_key_path, &key_settings.commitment_path)?);
+    }
+    for key in keys.iter() {
+        println!("loaded key {}", key.id);
+    }
+
+    Ok(keys)
+}
+
+// spent records are kept until their key is removed from the settings and this is run
+fn run_prune_spent(dal: &db::DAL) -> Result<(), Box<Error>> {
+    env_logger::try_init()?;
+    let settings : ServerSettings = ServerSettings::new("server_settings.yaml")?;
+    let keys = load_issuer_keys(&settings)?;
+    let token_key = load_token_key(&settings)?;
+    let public_token_key = load_public_token_key(&settings)?;
+
+    let mut active_key_ids : Vec<&str> = keys.iter().map(|k| k.id.as_str()).collect();
+    if let Some(ref token_key) = token_key {
+        active_key_ids.push(&token_key.id);
+    }
+    if let Some(ref public_token_key) = public_token_key {
+        active_key_ids.push(&public_token_key.id);
     }
+    let pruned = dal.prune_spent(&active_key_ids)?;
+    println!("pruned {} spent tokens of retired keys.", pruned);
+
+    Ok(())
 }
 
-fn run_server(dal: &mut db::DAL) -> Result<(), Box<Error>> {
+fn run_server(dal: db::DAL) -> Result<(), Box<Error>> {
     env_logger::try_init()?;
     let settings : ServerSettings = ServerSettings::new("server_settings.yaml")?;
-    let contents = fs::read_to_string(settings.secret_key_path)?;
 
-    let secret_key_pem = openssl::pkey::PKey::private_key_from_pem(&contents.into_bytes())?;
-    let secret_key_bytes = secret_key_pem.ec_key()?.private_key().to_vec();
+    let listener = TcpListener::bind(&settings.listen_address)?;
+    // non-blocking so the accept loop can notice a shutdown request
+    listener.set_nonblocking(true)?;
 
-    let listener = TcpListener::bind(settings.listen_address)?;
+    let keys = load_issuer_keys(&settings)?;
 
-    let commitment_struct : HashMap<String, String> = serde_json::from_str(&fs::read_to_string(settings.commitment_path)?)?;
+    let dal = Arc::new(dal);
+    let mut processor = ServerProcessor::new(keys, settings.max_tokens, dal)?;
+    processor.token_key = load_token_key(&settings)?;
+    processor.public_token_key = load_public_token_key(&settings)?;
+    processor.set_issue_workers(settings.issue_workers);
+    let processor = Arc::new(processor);
 
-    let mut rng = rand::thread_rng();
-    let mut processor = ServerProcessor::new(&secret_key_bytes, &base64::decode(&commitment_struct["G"])?, dal)?;
-    // accept connections and process them serially
-    for stream in listener.incoming() {
-        match handle_client(&mut stream?, &mut processor, &mut rng) {
-            Ok(()) => println!("stream finished successfully."),
-            Err(e) => println!("error occured: {}", e),
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGTERM, shutdown.clone())?;
+    signal_hook::flag::register(signal_hook::SIGINT, shutdown.clone())?;
+
+    let timeout = Duration::from_secs(settings.connection_timeout_secs);
+    let framing = settings.framing();
+    let pool = ThreadPool::new(settings.num_threads);
+
+    let http_thread = match settings.http_listen_address {
+        Some(ref address) => {
+            let server = tiny_http::Server::http(address.as_str()).map_err(|e| e.to_string())?;
+            let processor = processor.clone();
+            let pool = pool.clone();
+            let shutdown = shutdown.clone();
+            Some(thread::spawn(move || run_http_server(server, processor, pool, shutdown)))
+        },
+        None => None,
+    };
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            },
+            Err(e) => {
+                println!("error accepting connection: {}", e);
+                continue;
+            },
         };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let processor = processor.clone();
+        pool.execute(move || {
+            let mut rng = rand::thread_rng();
+            match handle_client(&mut stream, &processor, framing, &mut rng) {
+                Ok(()) => println!("stream finished successfully."),
+                Err(e) => println!("error occured: {}", e),
+            };
+        });
+    }
+
+    if let Some(http_thread) = http_thread {
+        let _ = http_thread.join();
     }
+    println!("shutting down, waiting for {} active connections.", pool.active_count());
+    pool.join();
 
     Ok(())
 }
 
 fn main() {
-    let mut dal = match db::DAL::new("tokens_server.db") {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 {
+        let run_result = match args[1].as_str() {
+            "keygen" => {
+                let settings = ServerSettings::new("server_settings.yaml");
+                if args.len() == 4 {
+                    run_keygen(&args[2], &args[3], settings.as_ref().ok())
+                } else {
+                    match settings {
+                        Ok(ref settings) => run_keygen(&settings.secret_key_path, &settings.commitment_path, Some(settings)),
+                        Err(e) => Err(e.into()),
+                    }
+                }
+            },
+            "prune-spent" => {
+                match db::DAL::new("tokens_server.db") {
+                    Ok(dal) => run_prune_spent(&dal),
+                    Err(e) => Err(e.into()),
+                }
+            },
+            "token-keygen" => {
+                if args.len() == 3 {
+                    run_token_keygen(&args[2])
+                } else {
+                    match ServerSettings::new("server_settings.yaml") {
+                        Ok(ServerSettings { token_secret_key_path: Some(ref path), .. }) => run_token_keygen(path),
+                        Ok(_) => Err("token_secret_key_path is not set.".into()),
+                        Err(e) => Err(e.into()),
+                    }
+                }
+            },
+            "public-token-keygen" => {
+                if args.len() == 3 {
+                    run_public_token_keygen(&args[2])
+                } else {
+                    match ServerSettings::new("server_settings.yaml") {
+                        Ok(ServerSettings { public_token_secret_key_path: Some(ref path), .. }) => run_public_token_keygen(path),
+                        Ok(_) => Err("public_token_secret_key_path is not set.".into()),
+                        Err(e) => Err(e.into()),
+                    }
+                }
+            },
+            "signing-keygen" => {
+                if args.len() < 4 {
+                    Err("not enough arguments.".into())
+                } else {
+                    run_signing_keygen(&args[2], &args[3])
+                }
+            },
+            _ => Err(format!("unknown command: {}", args[1]).into())
+        };
+        match run_result {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                println!("error: {}\n", e);
+                print_usage();
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let dal = match db::DAL::new("tokens_server.db") {
         Ok(d) => d,
         Err(e) => {
             println!("error: {}\n", e);
@@ -60,8 +496,24 @@ fn main() {
         }
     };
 
-    match run_server(&mut dal) {
+    match run_server(dal) {
         Ok(()) => println!("server finished successfully."),
         Err(e) => println!("error running server: {}", e),
     }
 }
+
+fn print_usage() {
+    let mut usage = String::new();
+    usage += "commands:";
+    usage += "\n\t(none):  run the server.";
+    usage += "\n\tkeygen [secret_key_path commitment_path]: generate a secret key and its commitment,";
+    usage += "\n\t         using the paths from server_settings.yaml if none are given.";
+    usage += "\n\t         with commitment_signing_key_path set, a signed version is added to the registry.";
+    usage += "\n\tprune-spent: drop spent token records of keys no longer in server_settings.yaml.";
+    usage += "\n\ttoken-keygen [token_secret_key_path]: generate a key for RFC 9578 token issuance.";
+    usage += "\n\tpublic-token-keygen [public_token_secret_key_path]: generate a 2048 bit rsa key for";
+    usage += "\n\t         publicly verifiable RFC 9578 tokens.";
+    usage += "\n\tsigning-keygen <signing_key_path> <verify_key_path>: generate a key for signing commitments.";
+
+    println!("{}", usage);
+}
@@ -0,0 +1,311 @@
+// RSA blind signatures with the RSABSSA-SHA384-PSS-Deterministic variant of RFC 9474:
+// EMSA-PSS with SHA-384, MGF1 with SHA-384 and a 48 byte salt, without a message randomizer.
+// signatures are plain RSASSA-PSS signatures, so verify only needs the public key.
+
+use super::error::{Error, Result};
+
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{HasPublic, Private, Public};
+use openssl::rsa::{Padding, Rsa, RsaRef};
+
+pub const HASH_SIZE: usize = 48;
+pub const SALT_SIZE: usize = 48;
+
+// DER of the RSASSA-PSS AlgorithmIdentifier with SHA-384, MGF1-SHA384 and a 48 byte salt
+const PSS_ALGORITHM_IDENTIFIER: &[u8] = &[
+    0x30, 0x3d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a, 0x30, 0x30, 0xa0,
+    0x0d, 0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0xa1, 0x1a,
+    0x30, 0x18, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08, 0x30, 0x0b, 0x06,
+    0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0xa2, 0x03, 0x02, 0x01, 0x30,
+];
+
+fn sha384(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(hash(MessageDigest::sha384(), data)?.to_vec())
+}
+
+fn mgf1(seed: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut mask = Vec::with_capacity(len + HASH_SIZE);
+    let mut counter: u32 = 0;
+    while mask.len() < len {
+        let mut input = seed.to_vec();
+        input.extend_from_slice(&[(counter >> 24) as u8, (counter >> 16) as u8, (counter >> 8) as u8, counter as u8]);
+        mask.extend(sha384(&input)?);
+        counter += 1;
+    }
+    mask.truncate(len);
+    Ok(mask)
+}
+
+// H(0x00 * 8 || H(msg) || salt)
+fn pss_digest(msg: &[u8], salt: &[u8]) -> Result<Vec<u8>> {
+    let mut m_prime = vec![0; 8];
+    m_prime.extend(sha384(msg)?);
+    m_prime.extend_from_slice(salt);
+    sha384(&m_prime)
+}
+
+fn emsa_pss_encode(msg: &[u8], em_bits: usize, salt: &[u8]) -> Result<Vec<u8>> {
+    let em_len = (em_bits + 7) / 8;
+    if em_len < HASH_SIZE + SALT_SIZE + 2 {
+        return Err(Error::InvalidInput("rsa modulus is too small".to_string()));
+    }
+
+    let h = pss_digest(msg, salt)?;
+    let mut db = vec![0; em_len - SALT_SIZE - HASH_SIZE - 2];
+    db.push(0x01);
+    db.extend_from_slice(salt);
+    let db_mask = mgf1(&h, em_len - HASH_SIZE - 1)?;
+    for i in 0..db.len() {
+        db[i] ^= db_mask[i];
+    }
+    db[0] &= 0xff >> (8*em_len - em_bits);
+
+    let mut em = db;
+    em.extend(h);
+    em.push(0xbc);
+    Ok(em)
+}
+
+fn emsa_pss_verify(msg: &[u8], em: &[u8], em_bits: usize) -> Result<()> {
+    let em_len = (em_bits + 7) / 8;
+    if em.len() != em_len || em_len < HASH_SIZE + SALT_SIZE + 2 || em[em_len - 1] != 0xbc {
+        return Err(Error::InvalidSignature);
+    }
+
+    let (masked_db, h) = em[..em_len - 1].split_at(em_len - HASH_SIZE - 1);
+    let top_bits = 0xff >> (8*em_len - em_bits);
+    if masked_db[0] & !top_bits != 0 {
+        return Err(Error::InvalidSignature);
+    }
+
+    let db_mask = mgf1(h, masked_db.len())?;
+    let mut db : Vec<u8> = masked_db.iter().zip(db_mask.iter()).map(|(m, d)| m ^ d).collect();
+    db[0] &= top_bits;
+
+    let ps_len = em_len - HASH_SIZE - SALT_SIZE - 2;
+    if db[..ps_len].iter().any(|b| *b != 0) || db[ps_len] != 0x01 {
+        return Err(Error::InvalidSignature);
+    }
+    if pss_digest(msg, &db[ps_len + 1..])? != h {
+        return Err(Error::InvalidSignature);
+    }
+
+    Ok(())
+}
+
+// I2OSP, failing if n doesn't fit in len bytes
+fn bn_to_bytes(n: &BigNumRef, len: usize) -> Result<Vec<u8>> {
+    let bytes = n.to_vec();
+    if bytes.len() > len {
+        return Err(Error::InvalidInput(format!("integer doesn't fit in {} bytes", len)));
+    }
+    let mut padded = vec![0; len - bytes.len()];
+    padded.extend(bytes);
+    Ok(padded)
+}
+
+// OS2IP, failing unless the result is below the modulus
+fn bytes_to_bn(bytes: &[u8], n: &BigNumRef) -> Result<BigNum> {
+    let m = BigNum::from_slice(bytes)?;
+    if &*m >= n {
+        return Err(Error::InvalidInput("integer is not below the modulus".to_string()));
+    }
+    Ok(m)
+}
+
+// RSAVP1, m = s^e mod n
+fn rsa_public<T: HasPublic>(pk: &RsaRef<T>, s: &BigNumRef) -> Result<BigNum> {
+    let mut ctx = BigNumContext::new()?;
+    let mut m = BigNum::new()?;
+    m.mod_exp(s, pk.e(), pk.n(), &mut ctx)?;
+    Ok(m)
+}
+
+fn em_bits<T: HasPublic>(pk: &RsaRef<T>) -> usize {
+    pk.n().num_bits() as usize - 1
+}
+
+pub fn modulus_len<T: HasPublic>(pk: &RsaRef<T>) -> usize {
+    pk.size() as usize
+}
+
+// returns the blinded message to send to the signer, and the inverse of the blind
+pub fn blind<T: HasPublic>(pk: &RsaRef<T>, msg: &[u8]) -> Result<(Vec<u8>, BigNum)> {
+    let mut salt = vec![0; SALT_SIZE];
+    openssl::rand::rand_bytes(&mut salt)?;
+    let encoded_msg = emsa_pss_encode(msg, em_bits(pk), &salt)?;
+
+    let n = pk.n();
+    let m = bytes_to_bn(&encoded_msg, n)?;
+    let mut ctx = BigNumContext::new()?;
+    let mut gcd = BigNum::new()?;
+    gcd.gcd(&m, n, &mut ctx)?;
+    if gcd != BigNum::from_u32(1)? {
+        return Err(Error::InvalidInput("message is not coprime with the modulus".to_string()));
+    }
+
+    // r is uniform in [1, n) and, with overwhelming probability, invertible
+    let mut r = BigNum::new()?;
+    let mut inv = BigNum::new()?;
+    loop {
+        n.rand_range(&mut r)?;
+        if r.num_bits() > 0 && inv.mod_inverse(&r, n, &mut ctx).is_ok() {
+            break;
+        }
+    }
+
+    let x = rsa_public(pk, &r)?;
+    let mut z = BigNum::new()?;
+    z.mod_mul(&m, &x, n, &mut ctx)?;
+
+    Ok((bn_to_bytes(&z, modulus_len(pk))?, inv))
+}
+
+// RSASP1 on the blinded message, checked with RSAVP1 so a faulty signature never leaks
+pub fn blind_sign(sk: &Rsa<Private>, blinded_msg: &[u8]) -> Result<Vec<u8>> {
+    let k = modulus_len(sk);
+    if blinded_msg.len() != k {
+        return Err(Error::InvalidInput(format!("blinded message of {} bytes", blinded_msg.len())));
+    }
+    let m = bytes_to_bn(blinded_msg, sk.n())?;
+
+    let mut blind_sig = vec![0; k];
+    sk.private_encrypt(blinded_msg, &mut blind_sig, Padding::NONE)?;
+    if rsa_public(sk, &BigNum::from_slice(&blind_sig)?)? != m {
+        return Err(Error::InvalidSignature);
+    }
+
+    Ok(blind_sig)
+}
+
+pub fn finalize<T: HasPublic>(pk: &RsaRef<T>, msg: &[u8], blind_sig: &[u8], inv: &BigNumRef) -> Result<Vec<u8>> {
+    let k = modulus_len(pk);
+    if blind_sig.len() != k {
+        return Err(Error::InvalidSignature);
+    }
+    let z = bytes_to_bn(blind_sig, pk.n()).map_err(|_| Error::InvalidSignature)?;
+
+    let mut ctx = BigNumContext::new()?;
+    let mut s = BigNum::new()?;
+    s.mod_mul(&z, inv, pk.n(), &mut ctx)?;
+    let sig = bn_to_bytes(&s, k)?;
+    verify(pk, msg, &sig)?;
+
+    Ok(sig)
+}
+
+// RSASSA-PSS-VERIFY
+pub fn verify<T: HasPublic>(pk: &RsaRef<T>, msg: &[u8], sig: &[u8]) -> Result<()> {
+    if sig.len() != modulus_len(pk) {
+        return Err(Error::InvalidSignature);
+    }
+    let s = bytes_to_bn(sig, pk.n()).map_err(|_| Error::InvalidSignature)?;
+    let m = rsa_public(pk, &s)?;
+
+    let em_bits = em_bits(pk);
+    let em = bn_to_bytes(&m, (em_bits + 7) / 8).map_err(|_| Error::InvalidSignature)?;
+    emsa_pss_verify(msg, &em, em_bits)
+}
+
+fn der_len(len: usize) -> Vec<u8> {
+    match len {
+        0..=0x7f => vec![len as u8],
+        0x80..=0xff => vec![0x81, len as u8],
+        _ => vec![0x82, (len >> 8) as u8, len as u8],
+    }
+}
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag];
+    tlv.extend(der_len(content.len()));
+    tlv.extend_from_slice(content);
+    tlv
+}
+
+// splits off the content of the first TLV, for definite lengths of up to two bytes
+fn der_read(bytes: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    let malformed = || Error::InvalidInput("malformed public key".to_string());
+    if bytes.len() < 2 || bytes[0] != tag {
+        return Err(malformed());
+    }
+    let (len, header) = match bytes[1] {
+        len @ 0..=0x7f => (len as usize, 2),
+        0x81 if bytes.len() >= 3 => (bytes[2] as usize, 3),
+        0x82 if bytes.len() >= 4 => (((bytes[2] as usize) << 8) | bytes[3] as usize, 4),
+        _ => return Err(malformed()),
+    };
+    if bytes.len() < header + len {
+        return Err(malformed());
+    }
+    Ok((&bytes[header..header + len], &bytes[header + len..]))
+}
+
+// SubjectPublicKeyInfo with the RSASSA-PSS algorithm identifier, as published by issuers
+pub fn public_key_to_spki<T: HasPublic>(pk: &RsaRef<T>) -> Result<Vec<u8>> {
+    let mut bit_string = vec![0];
+    bit_string.extend(pk.public_key_to_der_pkcs1()?);
+
+    let mut content = PSS_ALGORITHM_IDENTIFIER.to_vec();
+    content.extend(der_tlv(0x03, &bit_string));
+    Ok(der_tlv(0x30, &content))
+}
+
+pub fn public_key_from_spki(spki: &[u8]) -> Result<Rsa<Public>> {
+    let (content, rest) = der_read(spki, 0x30)?;
+    if !rest.is_empty() || !content.starts_with(PSS_ALGORITHM_IDENTIFIER) {
+        return Err(Error::InvalidInput("not an RSASSA-PSS public key".to_string()));
+    }
+    let (bit_string, rest) = der_read(&content[PSS_ALGORITHM_IDENTIFIER.len()..], 0x03)?;
+    if !rest.is_empty() || bit_string.first() != Some(&0) {
+        return Err(Error::InvalidInput("malformed public key".to_string()));
+    }
+
+    Ok(Rsa::public_key_from_der_pkcs1(&bit_string[1..])?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blind_sign() {
+        let sk = Rsa::generate(2048).unwrap();
+        let pk = Rsa::from_public_components(sk.n().to_owned().unwrap(), sk.e().to_owned().unwrap()).unwrap();
+        let msg = b"hello world";
+
+        let (blinded_msg, inv) = blind(&pk, msg).unwrap();
+        let blind_sig = blind_sign(&sk, &blinded_msg).unwrap();
+        let sig = finalize(&pk, msg, &blind_sig, &inv).unwrap();
+        verify(&pk, msg, &sig).unwrap();
+
+        // the signature is a standard PSS signature over the message itself
+        let pkey = openssl::pkey::PKey::from_rsa(
+            Rsa::from_public_components(sk.n().to_owned().unwrap(), sk.e().to_owned().unwrap()).unwrap()).unwrap();
+        let mut verifier = openssl::sign::Verifier::new(MessageDigest::sha384(), &pkey).unwrap();
+        verifier.set_rsa_padding(Padding::PKCS1_PSS).unwrap();
+        verifier.update(msg).unwrap();
+        assert!(verifier.verify(&sig).unwrap());
+
+        assert!(verify(&pk, b"hello world!", &sig).is_err());
+        let mut tampered = sig.clone();
+        tampered[10] ^= 1;
+        assert!(verify(&pk, msg, &tampered).is_err());
+
+        let other_sk = Rsa::generate(2048).unwrap();
+        let blind_sig = blind_sign(&other_sk, &blinded_msg).unwrap();
+        assert!(finalize(&pk, msg, &blind_sig, &inv).is_err());
+    }
+
+    #[test]
+    fn test_spki() {
+        let sk = Rsa::generate(2048).unwrap();
+        let spki = public_key_to_spki(&sk).unwrap();
+        assert!(hex::encode(&spki[..4]) == "30820152");
+
+        let pk = public_key_from_spki(&spki).unwrap();
+        assert!(*pk.n() == *sk.n());
+        assert!(public_key_from_spki(&spki[..spki.len() - 1]).is_err());
+        assert!(public_key_from_spki(&sk.public_key_to_der().unwrap()).is_err());
+    }
+}
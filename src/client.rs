@@ -1,43 +1,78 @@
 #![allow(non_snake_case)]
 
-use super::{hashes, random, converters, types, mac};
+use super::{hashes, random, converters, ecc, types, mac, net, http, db, commitment, token, voprf, blind_rsa};
+use super::group::Group;
+use super::secret::SecretScalar;
+use super::token::VoprfGroup;
+use super::error::{Error, Result};
 use rand::Rng;
-use std::error::Error;
+
+use std::collections::HashMap;
+use std::fs;
 
 use config::{ConfigError, Config, File};
+use openssl::bn::BigNum;
+use openssl::pkey::Public;
+use openssl::rsa::Rsa;
 
 #[derive(Debug, Deserialize)]
 pub struct ClientSettings {
     pub server_address: String,
     pub commitment_path: String,
     pub num_tokens: u8,
+    #[serde(default = "default_should_prepend_size")]
+    pub should_prepend_size: bool,
+    // latest version in the registry if not set
+    #[serde(default)]
+    pub commitment_version: Option<String>,
+    // required for signed registries, which are the only ones accepted once it is set
+    #[serde(default)]
+    pub commitment_verify_key_path: Option<String>,
+    // how long a token is held for a redemption before it can be used again, e.g. after a crash
+    #[serde(default = "default_redeem_lease_secs")]
+    pub redeem_lease_secs: u64,
+}
+
+fn default_should_prepend_size() -> bool {
+    true
+}
+
+fn default_redeem_lease_secs() -> u64 {
+    60
 }
 
 impl ClientSettings {
-    pub fn new(config_path: &str) -> Result<Self, ConfigError> {
+    pub fn new(config_path: &str) -> Result<Self> {
         let mut s = Config::new();
         s.merge(File::with_name(config_path))?;
-        s.try_into()
+        let settings : ClientSettings = s.try_into()?;
+        if settings.num_tokens == 0 {
+            return Err(ConfigError::Message("num_tokens must be at least 1".to_string()).into());
+        }
+
+        Ok(settings)
+    }
+
+    pub fn framing(&self) -> net::Framing {
+        net::Framing::from_prepend_size(self.should_prepend_size)
     }
 }
 
 
 #[allow(non_snake_case)]
-pub fn generate_and_blind_token<R: Rng>(rng: &mut R) -> (Vec<u8>, types::curve::big::BIG, types::curve::ecp::ECP) {
+// t and the blinding factor r are secrets, and are not logged
+pub fn generate_and_blind_token<R: Rng>(h2c: hashes::H2cMethod, rng: &mut R) -> Result<(Vec<u8>, SecretScalar, types::curve::ecp::ECP)> {
     let t = random::new_rand_vec(1024, rng);
-    debug!("t: {:x?}", t);
 
-    let T = hashes::hash_to_curve(&t).unwrap();
-    debug!("T: {}", T);
+    let T = hashes::hash_to_curve_with(h2c, &t)?;
 
     //let r = random::rand_big(&mut big_rng);
-    let r = random::rand_scalar_from_rng(rng);
-    debug!("r: {}", r);
+    let r = SecretScalar::new(random::rand_scalar_from_rng(rng));
 
     let M = T.mul(&r);
     debug!("M: {}", M);
 
-    (t, r, M)
+    Ok((t, r, M))
 }
 
 pub fn unblind_signature(Z : &types::curve::ecp::ECP, r: &types::curve::big::BIG) -> types::curve::ecp::ECP {
@@ -45,7 +80,7 @@ pub fn unblind_signature(Z : &types::curve::ecp::ECP, r: &types::curve::big::BIG
         let order = types::curve::big::BIG::new_ints(&types::curve::rom::CURVE_ORDER);
         let mut r_copy = types::curve::big::BIG::new_copy(r);
         r_copy.invmodp(&order);
-        r_copy
+        SecretScalar::new(r_copy)
     };
 
     let N = Z.mul(&r_inv);
@@ -55,16 +90,25 @@ pub fn unblind_signature(Z : &types::curve::ecp::ECP, r: &types::curve::big::BIG
 pub fn verify_dleq_proof(
     c: &types::curve::big::BIG, s: &types::curve::big::BIG,
     Z: &types::curve::ecp::ECP, M: &types::curve::ecp::ECP,
-    Y: &types::curve::ecp::ECP, G: &types::curve::ecp::ECP) -> Result<(), Box<Error>> {
+    Y: &types::curve::ecp::ECP, G: &types::curve::ecp::ECP) -> Result<()> {
 
     debug!("start verify_dleq_proof");
-    debug!("c,s: {}, {}", c, s);
     let mut A_calc = G.mul(s);
-    debug!("A_calc 1: {}", A_calc);
     A_calc.add(&Y.mul(c));
-    debug!("A_calc 2: {}", A_calc);
+
+    check_dleq_challenge(c, s, A_calc, Z, M, Y, G)
+}
+
+// A_calc = s*G + c*Y is computed by the caller, with or without tables
+fn check_dleq_challenge(
+    c: &types::curve::big::BIG, s: &types::curve::big::BIG,
+    mut A_calc: types::curve::ecp::ECP,
+    Z: &types::curve::ecp::ECP, M: &types::curve::ecp::ECP,
+    Y: &types::curve::ecp::ECP, G: &types::curve::ecp::ECP) -> Result<()> {
+
+    debug!("c,s: {}, {}", c, s);
     A_calc.affine();
-    debug!("A_calc 3: {}", A_calc);
+    debug!("A_calc: {}", A_calc);
 
     let mut B_calc = M.mul(s);
     B_calc.add(&Z.mul(c));
@@ -77,7 +121,51 @@ pub fn verify_dleq_proof(
     if *c == c_calc {
         return Ok(());
     } else {
-        return Err(format!("c and c' are different: {} != {}", c, c_calc).into());
+        debug!("c and c' are different: {} != {}", c, c_calc);
+        return Err(Error::InvalidProof);
+    }
+}
+
+// an issuer's G and Y with fixed-base tables, for checking many proofs under the same key
+pub struct DleqVerifier {
+    pub G: types::curve::ecp::ECP,
+    pub Y: types::curve::ecp::ECP,
+    pub G_table: ecc::FixedBaseTable,
+    pub Y_table: ecc::FixedBaseTable,
+}
+
+impl DleqVerifier {
+    pub fn new(G: &types::curve::ecp::ECP, Y: &types::curve::ecp::ECP) -> Self {
+        DleqVerifier {
+            G: *G,
+            Y: *Y,
+            G_table: ecc::FixedBaseTable::new(G),
+            Y_table: ecc::FixedBaseTable::new(Y),
+        }
+    }
+
+    pub fn verify_dleq_proof(
+        &self,
+        c: &types::curve::big::BIG, s: &types::curve::big::BIG,
+        Z: &types::curve::ecp::ECP, M: &types::curve::ecp::ECP) -> Result<()> {
+
+        let mut A_calc = self.G_table.mul(s);
+        A_calc.add(&self.Y_table.mul(c));
+
+        check_dleq_challenge(c, s, A_calc, Z, M, &self.Y, &self.G)
+    }
+
+    pub fn process_issue_response(
+        &self,
+        tokens: &[(Vec<u8>, SecretScalar, types::curve::ecp::ECP)], // (t, r, M)
+        signed_blinded_tokens: &[types::curve::ecp::ECP],
+        s: &types::curve::big::BIG, c: &types::curve::big::BIG) -> Result<Vec<types::curve::ecp::ECP>> {
+
+        check_token_count(tokens.len(), signed_blinded_tokens.len())?;
+        let (M, Z) = batch_composites(tokens, signed_blinded_tokens, &self.G, &self.Y)?;
+        self.verify_dleq_proof(c, s, &Z, &M)?;
+
+        unblind_signatures(tokens, signed_blinded_tokens)
     }
 }
 
@@ -86,23 +174,17 @@ pub fn mac(
     t: &[u8],
     N: &types::curve::ecp::ECP) -> Vec<u8> {
 
-    let T = hashes::hash_to_curve(t).unwrap();
-    debug!("Tx, Ty: {:x?}, {:x?}", T.getx(), T.gety());
     let sk = hashes::hash_for_redemption(t, N);
-    debug!("sk: {:x?}", sk);
-    let request_binding = hashes::hash_for_request_binding(&sk, shared_info);
-    debug!("request_binding: {:x?}", request_binding);
-
-    request_binding
+    hashes::hash_for_request_binding(&sk, shared_info)
 }
 
-pub fn prepare_issue_request<R: Rng>(num_tokens: u8, rng: &mut R) -> (types::ClientRequestWrapper,
-                                                                      Vec<(Vec<u8>, types::curve::big::BIG, types::curve::ecp::ECP)>) {
+pub fn prepare_issue_request<R: Rng>(num_tokens: u8, h2c: hashes::H2cMethod, rng: &mut R) -> Result<(types::ClientRequestWrapper,
+                                                                                                   Vec<(Vec<u8>, SecretScalar, types::curve::ecp::ECP)>)> {
     let mut tokens = vec![];
     let mut contents = vec![];
     let bytes_len = types::curve::big::MODBYTES + 1;
     for _i in 0..num_tokens {
-        let (t, r, M) = generate_and_blind_token(rng);
+        let (t, r, M) = generate_and_blind_token(h2c, rng)?;
         let mut bytes = vec![0; bytes_len];
         M.tobytes(&mut bytes, true);
         contents.push(base64::encode(&bytes));
@@ -115,19 +197,42 @@ pub fn prepare_issue_request<R: Rng>(num_tokens: u8, rng: &mut R) -> (types::Cli
     };
 
     let wrapped_req = types::ClientRequestWrapper {
-        bl_sig_req: base64::encode(&serde_json::to_string(&req).unwrap()),
+        bl_sig_req: base64::encode(&serde_json::to_string(&req)?),
         host: "".to_string(),
         http: "".to_string(),
     };
 
-    (wrapped_req, tokens)
+    Ok((wrapped_req, tokens))
 }
 
 pub fn process_issue_response(
-    tokens: &[(Vec<u8>, types::curve::big::BIG, types::curve::ecp::ECP)], // (t, r, M)
+    tokens: &[(Vec<u8>, SecretScalar, types::curve::ecp::ECP)], // (t, r, M)
     signed_blinded_tokens: &[types::curve::ecp::ECP],
     G: &types::curve::ecp::ECP, Y: &types::curve::ecp::ECP,
-    s: &types::curve::big::BIG, c: &types::curve::big::BIG) -> Result<Vec<types::curve::ecp::ECP>, Box<Error>> {
+    s: &types::curve::big::BIG, c: &types::curve::big::BIG) -> Result<Vec<types::curve::ecp::ECP>> {
+
+    check_token_count(tokens.len(), signed_blinded_tokens.len())?;
+    let (M, Z) = batch_composites(tokens, signed_blinded_tokens, G, Y)?;
+    verify_dleq_proof(&c, &s, &Z, &M, &Y, &G)?;
+
+    unblind_signatures(tokens, signed_blinded_tokens)
+}
+
+// an issuer must sign exactly the tokens that were requested
+fn check_token_count(requested: usize, received: usize) -> Result<()> {
+    if requested != received {
+        return Err(Error::WrongTokenCount { requested: requested, received: received });
+    }
+    Ok(())
+}
+
+// the composite M and Z of a batch, weighted by the same prng as the server's
+fn batch_composites(
+    tokens: &[(Vec<u8>, SecretScalar, types::curve::ecp::ECP)],
+    signed_blinded_tokens: &[types::curve::ecp::ECP],
+    G: &types::curve::ecp::ECP, Y: &types::curve::ecp::ECP) -> Result<(types::curve::ecp::ECP, types::curve::ecp::ECP)> {
+
+    check_token_count(tokens.len(), signed_blinded_tokens.len())?;
 
     let mut ps = vec![];
     ps.push(G);
@@ -144,27 +249,137 @@ pub fn process_issue_response(
 
     let mut prng = random::init_prng(&ps);
 
-    let mut M = types::curve::ecp::ECP::new();
-    let mut Z = types::curve::ecp::ECP::new();
-
+    let mut cs = vec![];
     for i in 0..tokens.len() {
         let c = random::rand_scalar_from_prng(&mut prng);
         debug!("c[{}]: {}", i, c);
-        M.add(&tokens[i].2.mul(&c));
-        Z.add(&signed_blinded_tokens[i].mul(&c));
+        cs.push(c);
     }
 
-    verify_dleq_proof(&c, &s, &Z, &M, &Y, &G)?;
+    let Ms : Vec<types::curve::ecp::ECP> = tokens.iter().map(|t| t.2).collect();
+    let M = ecc::multi_mul(&Ms, &cs);
+    let Z = ecc::multi_mul(signed_blinded_tokens, &cs);
+
+    Ok((M, Z))
+}
 
-    let mut unblinded_tokens = vec![];
-    for i in 0..signed_blinded_tokens.len() {
-        unblinded_tokens.push(unblind_signature(&signed_blinded_tokens[i], &tokens[i].1));
+// the inverses of all rs with a single invmodp, by Montgomery's trick. the rs are blinding
+// factors, so the products and inverses are secrets too
+fn batch_invert(rs: &[&types::curve::big::BIG]) -> Vec<SecretScalar> {
+    if rs.is_empty() {
+        return vec![];
+    }
+    let order = types::curve::big::BIG::new_ints(&types::curve::rom::CURVE_ORDER);
+
+    // products[i] = r_0 * ... * r_i
+    let mut products : Vec<SecretScalar> = Vec::with_capacity(rs.len());
+    products.push(SecretScalar::new(types::curve::big::BIG::new_copy(rs[0])));
+    for r in rs[1..].iter() {
+        let product = types::curve::big::BIG::modmul(&products[products.len() - 1], r, &order);
+        products.push(SecretScalar::new(product));
     }
 
-    Ok(unblinded_tokens)
+    let mut inv = {
+        let mut product = types::curve::big::BIG::new_copy(&products[rs.len() - 1]);
+        product.invmodp(&order);
+        SecretScalar::new(product)
+    };
+
+    let mut inverses = vec![];
+    for i in (1..rs.len()).rev() {
+        inverses.push(SecretScalar::new(types::curve::big::BIG::modmul(&inv, &products[i - 1], &order)));
+        inv = SecretScalar::new(types::curve::big::BIG::modmul(&inv, rs[i], &order));
+    }
+    inverses.push(inv);
+    inverses.reverse();
+
+    inverses
+}
+
+// unblinds all signed tokens as unblind_signature does, with a single inversion for the batch
+pub fn unblind_signatures(
+    tokens: &[(Vec<u8>, SecretScalar, types::curve::ecp::ECP)],
+    signed_blinded_tokens: &[types::curve::ecp::ECP]) -> Result<Vec<types::curve::ecp::ECP>> {
+
+    check_token_count(tokens.len(), signed_blinded_tokens.len())?;
+
+    let rs : Vec<&types::curve::big::BIG> = tokens.iter()
+        .map(|t| &*t.1)
+        .collect();
+    let r_invs = batch_invert(&rs);
+
+    let Ns = signed_blinded_tokens.iter()
+        .zip(r_invs.iter())
+        .map(|(Z, r_inv)| Z.mul(r_inv))
+        .collect();
+
+    Ok(Ns)
+}
+
+pub fn parse_batch_proof(batch_proof_str: &[u8]) -> Result<(types::curve::big::BIG, types::curve::big::BIG)> {
+    let batch_proof_struct : HashMap<String, String> = serde_json::from_slice(&batch_proof_str)?;
+    let proof_str = batch_proof_struct.get("P")
+        .ok_or_else(|| Error::InvalidInput("batch proof has no P".to_string()))?;
+    let proof_struct : HashMap<String, String> = serde_json::from_slice(&base64::decode(proof_str)?)?;
+    let s_str = proof_struct.get("R").ok_or_else(|| Error::InvalidInput("proof has no R".to_string()))?;
+    let c_str = proof_struct.get("C").ok_or_else(|| Error::InvalidInput("proof has no C".to_string()))?;
+    let s = converters::big_from_bytes(&base64::decode(s_str)?);
+    let c = converters::big_from_bytes(&base64::decode(c_str)?);
+
+    Ok((s, c))
+}
+
+// the signed points, s and c of the issuer's response to num_tokens blinded tokens. the response
+// is the signed points followed by the batch proof
+pub fn parse_issue_response(buf: &[u8], num_tokens: usize, key_id: &str) -> Result<(Vec<types::curve::ecp::ECP>, types::curve::big::BIG, types::curve::big::BIG)> {
+    let resp : Vec<String> = serde_json::from_slice(&base64::decode(buf)?)?;
+    debug!("resp: {:?}", resp);
+
+    let batch_proof_elem = match resp.last() {
+        Some(elem) => base64::decode(elem)?,
+        None => return Err(Error::InvalidInput("empty issue response".to_string())),
+    };
+    if !batch_proof_elem.starts_with(types::BATCH_PROOF_PREFIX.as_bytes()) {
+        return Err(Error::InvalidInput("issue response doesn't end with a batch proof".to_string()));
+    }
+    let batch_proof_str = &batch_proof_elem[types::BATCH_PROOF_PREFIX.len()..];
+    debug!("batch_proof: {}", String::from_utf8_lossy(batch_proof_str));
+
+    // servers that rotate keys name the issuing key in the batch proof
+    let batch_proof_struct : HashMap<String, String> = serde_json::from_slice(batch_proof_str)?;
+    if let Some(issuer_key_id) = batch_proof_struct.get(types::BATCH_PROOF_KEY_ID) {
+        if issuer_key_id != key_id {
+            return Err(Error::UnknownKey(issuer_key_id.clone()));
+        }
+    }
+
+    let num_points = resp.len() - 1;
+    if num_points != num_tokens {
+        return Err(Error::WrongTokenCount { requested: num_tokens, received: num_points });
+    }
+
+    let mut signed_blinded_tokens = vec![];
+    for elem in resp[..num_points].iter() {
+        signed_blinded_tokens.push(ecc::ecp_from_bytes(&base64::decode(elem)?)?);
+    }
+    debug!("parsed points");
+
+    let (s, c) = parse_batch_proof(batch_proof_str)?;
+    debug!("s,c: {},{}", s, c);
+
+    Ok((signed_blinded_tokens, s, c))
 }
 
-pub fn prepare_redeem_request(token: &[u8], N: &types::curve::ecp::ECP, host: &str, path: &str) -> Result<types::ClientRequestWrapper, Box<Error>> {
+// servers from before RedeemResponse answer successful redemptions with a bare "success"
+pub fn parse_redeem_response(buf: &[u8]) -> Result<types::RedeemResponse> {
+    if buf == b"success" {
+        return Ok(types::RedeemResponse::success());
+    }
+
+    Ok(serde_json::from_slice(buf)?)
+}
+
+pub fn prepare_redeem_request(token: &[u8], N: &types::curve::ecp::ECP, key_id: Option<&str>, host: &str, path: &str) -> Result<types::ClientRequestWrapper> {
 
     let mut contents = vec![];
     contents.push(base64::encode(token));
@@ -172,6 +387,9 @@ pub fn prepare_redeem_request(token: &[u8], N: &types::curve::ecp::ECP, host: &s
     let shared_info = mac::build_shared_info(host, path);
     let request_binding = mac(&shared_info, token, N);
     contents.push(base64::encode(&request_binding));
+    if let Some(key_id) = key_id {
+        contents.push(base64::encode(key_id.as_bytes()));
+    }
 
     let req = types::ClientRequest {
         type_f: "Redeem".to_string(),
@@ -187,31 +405,366 @@ pub fn prepare_redeem_request(token: &[u8], N: &types::curve::ecp::ECP, host: &s
     Ok(wrapped_req)
 }
 
+// sends a request to the issuer, returning its raw response
+pub trait Transport {
+    fn send_request(&self, request: &types::ClientRequestWrapper) -> Result<Vec<u8>>;
+}
+
+// the issuer's TCP endpoint, which issues and redeems
+pub struct TcpTransport {
+    pub address: String,
+    pub framing: net::Framing,
+}
+
+impl TcpTransport {
+    pub fn from_settings(settings: &ClientSettings) -> Self {
+        TcpTransport {
+            address: settings.server_address.clone(),
+            framing: settings.framing(),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_request(&self, request: &types::ClientRequestWrapper) -> Result<Vec<u8>> {
+        net::send_request(&self.address, request, self.framing)
+    }
+}
+
+// the challenge-bypass-token header of an HTTP request to url. only redeems
+pub struct HttpTransport {
+    pub url: String,
+}
+
+impl Transport for HttpTransport {
+    // answers with a RedeemResponse, like the TCP endpoint
+    fn send_request(&self, request: &types::ClientRequestWrapper) -> Result<Vec<u8>> {
+        let response = http::send_redemption(&self.url, request)?;
+        Ok(serde_json::to_vec(&response)?)
+    }
+}
+
+// where acquired tokens are kept until they are redeemed. a token is reserved while its
+// redemption is in flight, and only removed once the issuer accepts it
+pub trait TokenStore {
+    fn add_token(&mut self, token: &[u8], signed_token: &types::curve::ecp::ECP, key_id: &str) -> Result<()>;
+    fn reserve_token(&mut self, lease_secs: u64) -> Result<db::ReservedToken>;
+    fn commit_token(&mut self, num: i64) -> Result<()>;
+    fn release_token(&mut self, num: i64) -> Result<()>;
+    fn count_tokens(&self) -> Result<usize>;
+}
+
+impl TokenStore for db::DAL {
+    fn add_token(&mut self, token: &[u8], signed_token: &types::curve::ecp::ECP, key_id: &str) -> Result<()> {
+        db::DAL::add_token(self, token, signed_token, key_id)
+    }
 
+    fn reserve_token(&mut self, lease_secs: u64) -> Result<db::ReservedToken> {
+        db::DAL::reserve_token(self, lease_secs)
+    }
+
+    fn commit_token(&mut self, num: i64) -> Result<()> {
+        db::DAL::commit_token(self, num)
+    }
+
+    fn release_token(&mut self, num: i64) -> Result<()> {
+        db::DAL::release_token(self, num)
+    }
+
+    fn count_tokens(&self) -> Result<usize> {
+        db::DAL::count_tokens(self)
+    }
+}
+
+impl<'a, S: TokenStore> TokenStore for &'a mut S {
+    fn add_token(&mut self, token: &[u8], signed_token: &types::curve::ecp::ECP, key_id: &str) -> Result<()> {
+        (**self).add_token(token, signed_token, key_id)
+    }
+
+    fn reserve_token(&mut self, lease_secs: u64) -> Result<db::ReservedToken> {
+        (**self).reserve_token(lease_secs)
+    }
+
+    fn commit_token(&mut self, num: i64) -> Result<()> {
+        (**self).commit_token(num)
+    }
+
+    fn release_token(&mut self, num: i64) -> Result<()> {
+        (**self).release_token(num)
+    }
+
+    fn count_tokens(&self) -> Result<usize> {
+        (**self).count_tokens()
+    }
+}
+
+// acquires tokens from an issuer and redeems them, keeping them in store in between
+pub struct Client<T: Transport, S: TokenStore> {
+    pub verifier: DleqVerifier,
+    pub h2c: hashes::H2cMethod,
+    pub key_id: String,
+    // see ClientSettings::redeem_lease_secs
+    pub lease_secs: u64,
+    pub transport: T,
+    pub store: S,
+}
+
+impl<T: Transport, S: TokenStore> Client<T, S> {
+    // trusts the commitment at settings.commitment_path, checked as configured in settings
+    pub fn new(settings: &ClientSettings, transport: T, store: S) -> Result<Self> {
+        let verify_key = match settings.commitment_verify_key_path {
+            Some(ref path) => Some(openssl::pkey::PKey::public_key_from_pem(&fs::read(path)?)?),
+            None => None,
+        };
+        let (G, Y, h2c) = commitment::load_client_commitment(
+            &settings.commitment_path,
+            settings.commitment_version.as_ref().map(|v| v.as_str()),
+            verify_key.as_ref().map(|k| &**k))?;
+
+        let mut client = Client::with_commitment(&G, &Y, h2c, transport, store);
+        client.lease_secs = settings.redeem_lease_secs;
+        Ok(client)
+    }
+
+    pub fn with_commitment(
+        G: &types::curve::ecp::ECP, Y: &types::curve::ecp::ECP, h2c: hashes::H2cMethod,
+        transport: T, store: S) -> Self {
+
+        Client {
+            verifier: DleqVerifier::new(G, Y),
+            h2c: h2c,
+            key_id: hashes::hash_key_id(Y),
+            lease_secs: default_redeem_lease_secs(),
+            transport: transport,
+            store: store,
+        }
+    }
+
+    // requests num_tokens tokens and stores them once the issuer's proof checks out
+    pub fn acquire(&mut self, num_tokens: u8) -> Result<usize> {
+        let mut rng = rand::thread_rng();
+        let (request, tokens) = prepare_issue_request(num_tokens, self.h2c, &mut rng)?;
+
+        let buf = self.transport.send_request(&request)?;
+        let (signed_blinded_tokens, s, c) = parse_issue_response(&buf, tokens.len(), &self.key_id)?;
+        let unblinded_tokens = self.verifier.process_issue_response(&tokens, &signed_blinded_tokens, &s, &c)?;
+        for (token, N) in tokens.iter().zip(unblinded_tokens.iter()) {
+            self.store.add_token(&token.0, N, &self.key_id)?;
+        }
+
+        Ok(unblinded_tokens.len())
+    }
+
+    // redeems the next stored token for host and path. the token is removed once the issuer
+    // accepts it or rejects it for good, and is kept for another try if the request or the
+    // issuer fails
+    pub fn redeem(&mut self, host: &str, path: &str) -> Result<()> {
+        let reserved = self.store.reserve_token(self.lease_secs)?;
+        let token = &reserved.token;
+
+        let key_id = token.key_id.as_ref().map(|k| k.as_str());
+        let result = prepare_redeem_request(&token.token, &token.signed_token, key_id, host, path)
+            .and_then(|redeem_request| self.transport.send_request(&redeem_request))
+            .and_then(|buf| parse_redeem_response(&buf));
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.store.release_token(reserved.num)?;
+                return Err(e);
+            },
+        };
+        debug!("redeem response: {:?}", response);
+
+        match response.status {
+            types::RedeemStatus::Success => {
+                self.store.commit_token(reserved.num)?;
+                Ok(())
+            },
+            // a spent token, or one that doesn't verify under a key the issuer has, is no use
+            types::RedeemStatus::DoubleSpend => {
+                self.store.commit_token(reserved.num)?;
+                Err(Error::DoubleSpend)
+            },
+            types::RedeemStatus::BadBinding => {
+                self.store.commit_token(reserved.num)?;
+                Err(Error::InvalidMac)
+            },
+            types::RedeemStatus::UnknownKey => {
+                self.store.commit_token(reserved.num)?;
+                Err(Error::UnknownKey(response.message))
+            },
+            types::RedeemStatus::ServerError => {
+                self.store.release_token(reserved.num)?;
+                Err(Error::RedemptionFailed(response.message))
+            },
+        }
+    }
+
+    pub fn balance(&self) -> Result<usize> {
+        self.store.count_tokens()
+    }
+}
+
+// what the client keeps between a type 0x0001 token request and the issuer's response
+pub struct PendingToken {
+    pub token_input: Vec<u8>,
+    pub nonce: [u8; token::NONCE_SIZE],
+    pub challenge_digest: [u8; token::DIGEST_SIZE],
+    pub token_key_id: [u8; token::KEY_ID_SIZE],
+    pub blind: SecretScalar<<VoprfGroup as Group>::Scalar>,
+    pub blinded_element: <VoprfGroup as Group>::Element,
+}
+
+pub fn prepare_token_request<R: Rng>(
+    challenge: &token::TokenChallenge, issuer_key: &<VoprfGroup as Group>::Element,
+    rng: &mut R) -> Result<(token::TokenRequest, PendingToken)> {
+
+    if challenge.token_type != token::TOKEN_TYPE_VOPRF {
+        return Err(Error::UnsupportedTokenType(challenge.token_type));
+    }
+
+    let token_key_id = token::token_key_id(&VoprfGroup::serialize_element(issuer_key));
+    let mut nonce = [0; token::NONCE_SIZE];
+    rng.fill(&mut nonce);
+    let challenge_digest = challenge.digest()?;
+    let token_input = token::token_input(token::TOKEN_TYPE_VOPRF, &nonce, &challenge_digest, &token_key_id);
+    let (blind, blinded_element) = voprf::blind::<VoprfGroup, _>(&token_input, rng)?;
+
+    let request = token::TokenRequest {
+        token_type: token::TOKEN_TYPE_VOPRF,
+        truncated_token_key_id: token::truncate_key_id(&token_key_id),
+        blinded_msg: VoprfGroup::serialize_element(&blinded_element),
+    };
+    let pending = PendingToken {
+        token_input: token_input,
+        nonce: nonce,
+        challenge_digest: challenge_digest,
+        token_key_id: token_key_id,
+        blind: SecretScalar::new(blind),
+        blinded_element: blinded_element,
+    };
+
+    Ok((request, pending))
+}
+
+pub fn finalize_token(pending: &PendingToken, response: &[u8], issuer_key: &<VoprfGroup as Group>::Element) -> Result<token::Token> {
+    let response = token::TokenResponse::from_bytes(response)?;
+    let evaluated_element = VoprfGroup::deserialize_element(&response.evaluate_msg)?;
+    let proof = voprf::Proof::<VoprfGroup>::from_bytes(&response.evaluate_proof)?;
+
+    let mut outputs = voprf::finalize::<VoprfGroup>(
+        &[pending.token_input.as_slice()], std::slice::from_ref(&*pending.blind),
+        &[evaluated_element], &[pending.blinded_element],
+        issuer_key, &proof)?;
+
+    let token = token::Token {
+        token_type: token::TOKEN_TYPE_VOPRF,
+        nonce: pending.nonce,
+        challenge_digest: pending.challenge_digest,
+        token_key_id: pending.token_key_id,
+        authenticator: outputs.remove(0),
+    };
+
+    Ok(token)
+}
+
+// what the client keeps between a type 0x0002 token request and the issuer's response
+pub struct PendingPublicToken {
+    pub token_input: Vec<u8>,
+    pub nonce: [u8; token::NONCE_SIZE],
+    pub challenge_digest: [u8; token::DIGEST_SIZE],
+    pub token_key_id: [u8; token::KEY_ID_SIZE],
+    pub inv: BigNum,
+}
+
+// the inverse of the blind would let the issuer link the request to the token
+impl Drop for PendingPublicToken {
+    fn drop(&mut self) {
+        self.inv.clear();
+    }
+}
+
+pub fn prepare_public_token_request<R: Rng>(
+    challenge: &token::TokenChallenge, issuer_key: &Rsa<Public>,
+    rng: &mut R) -> Result<(token::TokenRequest, PendingPublicToken)> {
+
+    if challenge.token_type != token::TOKEN_TYPE_BLIND_RSA {
+        return Err(Error::UnsupportedTokenType(challenge.token_type));
+    }
+    if blind_rsa::modulus_len(issuer_key) != token::BLIND_RSA_KEY_SIZE {
+        return Err(Error::InvalidInput("issuer key is not a 2048 bit rsa key".to_string()));
+    }
+
+    let token_key_id = token::token_key_id(&blind_rsa::public_key_to_spki(issuer_key)?);
+    let mut nonce = [0; token::NONCE_SIZE];
+    rng.fill(&mut nonce);
+    let challenge_digest = challenge.digest()?;
+    let token_input = token::token_input(token::TOKEN_TYPE_BLIND_RSA, &nonce, &challenge_digest, &token_key_id);
+    let (blinded_msg, inv) = blind_rsa::blind(issuer_key, &token_input)?;
+
+    let request = token::TokenRequest {
+        token_type: token::TOKEN_TYPE_BLIND_RSA,
+        truncated_token_key_id: token::truncate_key_id(&token_key_id),
+        blinded_msg: blinded_msg,
+    };
+    let pending = PendingPublicToken {
+        token_input: token_input,
+        nonce: nonce,
+        challenge_digest: challenge_digest,
+        token_key_id: token_key_id,
+        inv: inv,
+    };
+
+    Ok((request, pending))
+}
+
+// the response to a type 0x0002 request is the bare blind signature
+pub fn finalize_public_token(pending: &PendingPublicToken, response: &[u8], issuer_key: &Rsa<Public>) -> Result<token::Token> {
+    let authenticator = blind_rsa::finalize(issuer_key, &pending.token_input, response, &pending.inv)?;
+
+    let token = token::Token {
+        token_type: token::TOKEN_TYPE_BLIND_RSA,
+        nonce: pending.nonce,
+        challenge_digest: pending.challenge_digest,
+        token_key_id: pending.token_key_id,
+        authenticator: authenticator,
+    };
+
+    Ok(token)
+}
+
+
+#[cfg(test)]
 mod test {
+    use super::*;
+    use crate::server::ServerProcessor;
+    use crate::server::test::{generator, setup, temp_dal};
 
     #[test]
     fn test_client() {
-        env_logger::try_init();
+        let _ = env_logger::try_init();
         let mut rng = rand::thread_rng();
 
-        let (t, r, M) = generate_and_blind_token(&mut rng);
         let mut big_rng = random::new_rand(&mut rng);
         let x = random::rand_big(&mut big_rng);
-        let Z = M.mul(&x);
-        let N = unblind_signature(&Z, &r);
-
-        let T = hashes::hash_to_curve(&t).unwrap();
-        let expected_N = T.mul(&x);
-        assert!(expected_N == N);
+        for h2c in [hashes::H2cMethod::Increment, hashes::H2cMethod::Swu].iter() {
+            let (t, r, M) = generate_and_blind_token(*h2c, &mut rng).unwrap();
+            let Z = M.mul(&x);
+            let N = unblind_signature(&Z, &r);
+
+            let T = hashes::hash_to_curve_with(*h2c, &t).unwrap();
+            let expected_N = T.mul(&x);
+            assert!(expected_N == N);
+        }
     }
 
     #[test]
     fn test_mac() {
-        env_logger::try_init();
+        let _ = env_logger::try_init();
         let mut rng = rand::thread_rng();
 
-        let (t, r, M) = generate_and_blind_token(&mut rng);
+        let (t, r, M) = generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
         let mut big_rng = random::new_rand(&mut rng);
         let x = random::rand_big(&mut big_rng);
         let Z = M.mul(&x);
@@ -219,4 +772,101 @@ mod test {
 
         mac(&[1,2,3], &t, &N);
     }
+
+    #[test]
+    fn test_unblind_signatures() {
+        let mut rng = rand::thread_rng();
+        let mut big_rng = random::new_rand(&mut rng);
+        let x = random::rand_big(&mut big_rng);
+
+        assert!(unblind_signatures(&[], &[]).unwrap().is_empty());
+        for n in [1, 2, 7, 50].iter() {
+            let mut tokens = vec![];
+            let mut Zs = vec![];
+            for _ in 0..*n {
+                let token = generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+                Zs.push(token.2.mul(&x));
+                tokens.push(token);
+            }
+
+            let Ns = unblind_signatures(&tokens, &Zs).unwrap();
+            assert!(Ns.len() == *n);
+            for ((N, Z), token) in Ns.iter().zip(Zs.iter()).zip(tokens.iter()) {
+                assert!(*N == unblind_signature(Z, &token.1));
+            }
+
+            // more or fewer signed points than tokens are refused instead of panicking
+            let mut more = Zs.clone();
+            more.push(Zs[0]);
+            assert!(matches!(unblind_signatures(&tokens, &more), Err(Error::WrongTokenCount { requested, received }) if requested == *n && received == *n + 1));
+            assert!(matches!(unblind_signatures(&tokens, &Zs[..*n - 1]), Err(Error::WrongTokenCount { requested, received }) if requested == *n && received == *n - 1));
+        }
+    }
+
+    // an in-process issuer for Client
+    struct Loopback<'a>(&'a ServerProcessor);
+
+    impl<'a> Transport for Loopback<'a> {
+        fn send_request(&self, request: &types::ClientRequestWrapper) -> Result<Vec<u8>> {
+            let msg = serde_json::to_vec(request)?;
+            self.0.process_server_message(&msg, &mut rand::thread_rng()).map(|resp| resp.into_bytes())
+        }
+    }
+
+    // sends every request twice, as a client retrying after a lost response would
+    struct Replay<'a>(&'a ServerProcessor);
+
+    impl<'a> Transport for Replay<'a> {
+        fn send_request(&self, request: &types::ClientRequestWrapper) -> Result<Vec<u8>> {
+            Transport::send_request(&Loopback(self.0), request)?;
+            Transport::send_request(&Loopback(self.0), request)
+        }
+    }
+
+    struct Offline;
+
+    impl Transport for Offline {
+        fn send_request(&self, _: &types::ClientRequestWrapper) -> Result<Vec<u8>> {
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into())
+        }
+    }
+
+    #[test]
+    fn test_client_api() {
+        let (processor, x, _) = setup("client-api", hashes::H2cMethod::Increment);
+        let G = generator();
+        let Y = G.mul(&x);
+        let mut store = temp_dal("client-api-store");
+
+        {
+            let mut client = Client::with_commitment(
+                &G, &Y, hashes::H2cMethod::Increment, Loopback(&processor), &mut store);
+            assert!(client.acquire(5).unwrap() == 5);
+            assert!(client.balance().unwrap() == 5);
+            client.redeem("example.com", "/").unwrap();
+            assert!(client.balance().unwrap() == 4);
+        }
+
+        // a token the issuer has already seen is dropped
+        {
+            let mut client = Client::with_commitment(
+                &G, &Y, hashes::H2cMethod::Increment, Replay(&processor), &mut store);
+            assert!(matches!(client.redeem("example.com", "/"), Err(Error::DoubleSpend)));
+            assert!(client.balance().unwrap() == 3);
+        }
+
+        // a failed redemption keeps the token
+        {
+            let mut client = Client::with_commitment(
+                &G, &Y, hashes::H2cMethod::Increment, Offline, &mut store);
+            assert!(client.redeem("example.com", "/").is_err());
+            assert!(client.balance().unwrap() == 3);
+        }
+
+        // a client trusting another key stores nothing
+        let mut other = Client::with_commitment(
+            &G, &G.mul(&converters::big_from_int(7)), hashes::H2cMethod::Increment, Loopback(&processor), temp_dal("client-api-other"));
+        assert!(other.acquire(1).is_err());
+        assert!(other.balance().unwrap() == 0);
+    }
 }
@@ -0,0 +1,300 @@
+#![allow(non_snake_case)]
+
+use super::{ecc, hashes, types};
+use super::error::{Error, Result};
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKeyRef, Private, Public};
+use openssl::sign::{Signer, Verifier};
+
+// one version of the issuer's commitment, as published in the registry file:
+// {"1.0": {"G": ..., "H": ..., "expiry": ..., "sig": ...}, "1.01": {...}}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitmentEntry {
+    pub G: String,
+    pub H: String,
+    // left out for increment, so entries signed before swu existed still verify
+    #[serde(default, skip_serializing_if = "hashes::H2cMethod::is_increment")]
+    pub h2c: hashes::H2cMethod,
+    // seconds since the unix epoch
+    pub expiry: u64,
+    // base64 DER ECDSA-SHA256 signature over signed_payload
+    pub sig: String,
+}
+
+pub type CommitmentRegistry = BTreeMap<String, CommitmentEntry>;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommitmentFile {
+    Registry(CommitmentRegistry),
+    // bare {"G": ..., "H": ..., "h2c": ...} map, unversioned and unsigned
+    Legacy(HashMap<String, String>),
+}
+
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    version: &'a str,
+    G: &'a str,
+    H: &'a str,
+    #[serde(skip_serializing_if = "hashes::H2cMethod::is_increment")]
+    h2c: hashes::H2cMethod,
+    expiry: u64,
+}
+
+fn signed_payload(version: &str, entry: &CommitmentEntry) -> Result<Vec<u8>> {
+    let fields = SignedFields {
+        version: version,
+        G: &entry.G,
+        H: &entry.H,
+        h2c: entry.h2c,
+        expiry: entry.expiry,
+    };
+    Ok(serde_json::to_vec(&fields)?)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn sign_entry(
+    version: &str,
+    G: &types::curve::ecp::ECP, H: &types::curve::ecp::ECP, h2c: hashes::H2cMethod,
+    expiry: u64, signing_key: &PKeyRef<Private>) -> Result<CommitmentEntry> {
+
+    let mut entry = CommitmentEntry {
+        G: base64::encode(&ecc::ecp_to_bytes(G, false)),
+        H: base64::encode(&ecc::ecp_to_bytes(H, false)),
+        h2c: h2c,
+        expiry: expiry,
+        sig: String::new(),
+    };
+
+    let mut signer = Signer::new(MessageDigest::sha256(), signing_key)?;
+    signer.update(&signed_payload(version, &entry)?)?;
+    entry.sig = base64::encode(&signer.sign_to_vec()?);
+
+    Ok(entry)
+}
+
+pub fn verify_entry(version: &str, entry: &CommitmentEntry, verify_key: &PKeyRef<Public>, now: u64) -> Result<()> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), verify_key)?;
+    verifier.update(&signed_payload(version, entry)?)?;
+    if !verifier.verify(&base64::decode(&entry.sig)?)? {
+        return Err(Error::InvalidCommitment(format!("bad signature on version {}", version)));
+    }
+    if entry.expiry <= now {
+        return Err(Error::CommitmentExpired(version.to_string()));
+    }
+
+    Ok(())
+}
+
+fn decode_points(G: &str, H: &str) -> Result<(types::curve::ecp::ECP, types::curve::ecp::ECP)> {
+    Ok((ecc::ecp_from_bytes(&base64::decode(G)?)?, ecc::ecp_from_bytes(&base64::decode(H)?)?))
+}
+
+// (G, H, h2c) as written in a bare commitment, the method defaulting to increment
+fn legacy_fields(legacy: &HashMap<String, String>) -> Result<(&str, &str, hashes::H2cMethod)> {
+    let G = legacy.get("G").ok_or_else(|| Error::InvalidCommitment("missing G".to_string()))?;
+    let H = legacy.get("H").ok_or_else(|| Error::InvalidCommitment("missing H".to_string()))?;
+    let h2c = match legacy.get("h2c") {
+        Some(h2c) => hashes::H2cMethod::from_name(h2c)
+            .ok_or_else(|| Error::InvalidCommitment(format!("unknown h2c method {}", h2c)))?,
+        None => hashes::H2cMethod::Increment,
+    };
+    Ok((G.as_str(), H.as_str(), h2c))
+}
+
+// orders versions by their dot-separated numbers, so "1.10" comes after "1.9". parts that aren't
+// numbers count as 0, and ties fall back to the strings
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let numbers = |v: &str| -> Vec<u64> {
+        v.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+    };
+    numbers(a).cmp(&numbers(b)).then_with(|| a.cmp(b))
+}
+
+// returns (G, H, h2c) for the requested version, or the latest one if none is requested.
+// registries are only trusted with a verification key, and bare commitments only without one.
+pub fn parse_client_commitment(
+    contents: &str, version: Option<&str>,
+    verify_key: Option<&PKeyRef<Public>>, now: u64) -> Result<(types::curve::ecp::ECP, types::curve::ecp::ECP, hashes::H2cMethod)> {
+
+    match (serde_json::from_str::<CommitmentFile>(contents)?, verify_key) {
+        (CommitmentFile::Registry(registry), Some(verify_key)) => {
+            let (version, entry) = match version {
+                Some(version) => (version, registry.get(version)),
+                None => match registry.iter().max_by(|a, b| compare_versions(a.0, b.0)) {
+                    Some((version, entry)) => (version.as_str(), Some(entry)),
+                    None => return Err(Error::InvalidCommitment("empty registry".to_string())),
+                },
+            };
+            let entry = entry.ok_or_else(|| Error::InvalidCommitment(format!("no version {}", version)))?;
+            verify_entry(version, entry, verify_key, now)?;
+            let (G, H) = decode_points(&entry.G, &entry.H)?;
+            Ok((G, H, entry.h2c))
+        },
+        (CommitmentFile::Registry(_), None) =>
+            Err(Error::InvalidCommitment("no key to verify the registry with".to_string())),
+        (CommitmentFile::Legacy(_), Some(_)) =>
+            Err(Error::InvalidCommitment("commitment is not signed".to_string())),
+        (CommitmentFile::Legacy(legacy), None) => {
+            let (G, H, h2c) = legacy_fields(&legacy)?;
+            let (G, H) = decode_points(G, H)?;
+            Ok((G, H, h2c))
+        },
+    }
+}
+
+pub fn load_client_commitment(
+    path: &str, version: Option<&str>,
+    verify_key: Option<&PKeyRef<Public>>) -> Result<(types::curve::ecp::ECP, types::curve::ecp::ECP, hashes::H2cMethod)> {
+
+    parse_client_commitment(&fs::read_to_string(path)?, version, verify_key, now())
+}
+
+// the issuer trusts its own file, so every (G, H, h2c) is returned without verification
+pub fn load_all_commitments(path: &str) -> Result<Vec<(Vec<u8>, Vec<u8>, hashes::H2cMethod)>> {
+    let decode = |G: &str, H: &str, h2c: hashes::H2cMethod| -> Result<(Vec<u8>, Vec<u8>, hashes::H2cMethod)> {
+        Ok((base64::decode(G)?, base64::decode(H)?, h2c))
+    };
+
+    match serde_json::from_str::<CommitmentFile>(&fs::read_to_string(path)?)? {
+        CommitmentFile::Registry(registry) => registry.values()
+            .map(|entry| decode(&entry.G, &entry.H, entry.h2c))
+            .collect(),
+        CommitmentFile::Legacy(legacy) => {
+            let (G, H, h2c) = legacy_fields(&legacy)?;
+            Ok(vec![decode(G, H, h2c)?])
+        },
+    }
+}
+
+pub fn load_registry(path: &str) -> Result<CommitmentRegistry> {
+    match serde_json::from_str::<CommitmentFile>(&fs::read_to_string(path)?)? {
+        CommitmentFile::Registry(registry) => Ok(registry),
+        CommitmentFile::Legacy(_) => Err(Error::InvalidCommitment("commitment is not a registry".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+
+    fn signing_keys() -> (PKey<Private>, PKey<Public>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let signing_key = EcKey::generate(&group).unwrap();
+        let verify_key = EcKey::from_public_key(&group, signing_key.public_key()).unwrap();
+        (PKey::from_ec_key(signing_key).unwrap(), PKey::from_ec_key(verify_key).unwrap())
+    }
+
+    fn registry_contents(signing_key: &PKeyRef<Private>) -> (String, types::curve::ecp::ECP, types::curve::ecp::ECP) {
+        let G = hashes::hash_to_curve(b"G").unwrap();
+        let H = hashes::hash_to_curve(b"H").unwrap();
+        let old_H = hashes::hash_to_curve(b"old H").unwrap();
+
+        let mut registry = CommitmentRegistry::new();
+        registry.insert("1.0".to_string(), sign_entry("1.0", &G, &old_H, hashes::H2cMethod::Increment, 2000, signing_key).unwrap());
+        registry.insert("1.01".to_string(), sign_entry("1.01", &G, &H, hashes::H2cMethod::Swu, 2000, signing_key).unwrap());
+        (serde_json::to_string(&registry).unwrap(), G, H)
+    }
+
+    #[test]
+    fn test_signed_registry() {
+        let (signing_key, verify_key) = signing_keys();
+        let (contents, G, H) = registry_contents(&signing_key);
+
+        let (G_parsed, H_parsed, h2c) = parse_client_commitment(&contents, None, Some(&*verify_key), 1000).unwrap();
+        assert!(G_parsed == G);
+        assert!(H_parsed == H);
+        assert!(h2c == hashes::H2cMethod::Swu);
+
+        let (_, H_parsed, _) = parse_client_commitment(&contents, Some("1.01"), Some(&*verify_key), 1000).unwrap();
+        assert!(H_parsed == H);
+        let (_, _, h2c) = parse_client_commitment(&contents, Some("1.0"), Some(&*verify_key), 1000).unwrap();
+        assert!(h2c == hashes::H2cMethod::Increment);
+        assert!(parse_client_commitment(&contents, Some("1.02"), Some(&*verify_key), 1000).is_err());
+        assert!(parse_client_commitment(&contents, None, None, 1000).is_err());
+
+        assert!(matches!(parse_client_commitment(&contents, None, Some(&*verify_key), 2000), Err(Error::CommitmentExpired(_))));
+
+        let (_, other_verify_key) = signing_keys();
+        assert!(parse_client_commitment(&contents, None, Some(&*other_verify_key), 1000).is_err());
+    }
+
+    #[test]
+    fn test_latest_version() {
+        let (signing_key, verify_key) = signing_keys();
+        let G = hashes::hash_to_curve(b"G").unwrap();
+        let H = hashes::hash_to_curve(b"H").unwrap();
+        let old_H = hashes::hash_to_curve(b"old H").unwrap();
+
+        let mut registry = CommitmentRegistry::new();
+        registry.insert("1.9".to_string(), sign_entry("1.9", &G, &old_H, hashes::H2cMethod::Increment, 2000, &signing_key).unwrap());
+        registry.insert("1.10".to_string(), sign_entry("1.10", &G, &H, hashes::H2cMethod::Increment, 2000, &signing_key).unwrap());
+        let contents = serde_json::to_string(&registry).unwrap();
+
+        let (_, H_parsed, _) = parse_client_commitment(&contents, None, Some(&*verify_key), 1000).unwrap();
+        assert!(H_parsed == H);
+
+        assert!(compare_versions("1.01", "1.0") == Ordering::Greater);
+        assert!(compare_versions("2.0", "1.10") == Ordering::Greater);
+    }
+
+    #[test]
+    fn test_tampered_registry() {
+        let (signing_key, verify_key) = signing_keys();
+        let (contents, G, _) = registry_contents(&signing_key);
+
+        let mut registry : CommitmentRegistry = serde_json::from_str(&contents).unwrap();
+        registry.get_mut("1.01").unwrap().H = base64::encode(&ecc::ecp_to_bytes(&G, false));
+        let tampered = serde_json::to_string(&registry).unwrap();
+        assert!(parse_client_commitment(&tampered, None, Some(&*verify_key), 1000).is_err());
+
+        let mut registry : CommitmentRegistry = serde_json::from_str(&contents).unwrap();
+        registry.get_mut("1.01").unwrap().expiry = 3000;
+        let tampered = serde_json::to_string(&registry).unwrap();
+        assert!(parse_client_commitment(&tampered, None, Some(&*verify_key), 2500).is_err());
+
+        let mut registry : CommitmentRegistry = serde_json::from_str(&contents).unwrap();
+        registry.get_mut("1.01").unwrap().h2c = hashes::H2cMethod::Increment;
+        let tampered = serde_json::to_string(&registry).unwrap();
+        assert!(parse_client_commitment(&tampered, None, Some(&*verify_key), 1000).is_err());
+    }
+
+    #[test]
+    fn test_legacy_commitment() {
+        let G = hashes::hash_to_curve(b"G").unwrap();
+        let H = hashes::hash_to_curve(b"H").unwrap();
+        let mut legacy = HashMap::new();
+        legacy.insert("G".to_string(), base64::encode(&ecc::ecp_to_bytes(&G, false)));
+        legacy.insert("H".to_string(), base64::encode(&ecc::ecp_to_bytes(&H, false)));
+        let contents = serde_json::to_string(&legacy).unwrap();
+
+        let (_, H_parsed, h2c) = parse_client_commitment(&contents, None, None, 1000).unwrap();
+        assert!(H_parsed == H);
+        assert!(h2c == hashes::H2cMethod::Increment);
+
+        let (_, verify_key) = signing_keys();
+        assert!(parse_client_commitment(&contents, None, Some(&*verify_key), 1000).is_err());
+
+        legacy.insert("h2c".to_string(), "swu".to_string());
+        let contents = serde_json::to_string(&legacy).unwrap();
+        let (_, _, h2c) = parse_client_commitment(&contents, None, None, 1000).unwrap();
+        assert!(h2c == hashes::H2cMethod::Swu);
+
+        legacy.insert("h2c".to_string(), "elligator".to_string());
+        let contents = serde_json::to_string(&legacy).unwrap();
+        assert!(parse_client_commitment(&contents, None, None, 1000).is_err());
+    }
+}
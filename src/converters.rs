@@ -16,6 +16,21 @@ pub fn big_from_bytes(n: &[u8]) -> BIG {
     m
 }
 
+// defines dbig_from_bytes for the DBIG in scope, for the curves of h2c (h2c::p256 for the legacy
+// one). for inputs of up to 2*MODBYTES bytes, e.g. before reducing them modulo p or the curve order
+macro_rules! dbig_from_bytes {
+    () => {
+        pub fn dbig_from_bytes(n: &[u8]) -> DBIG {
+            let mut m = DBIG::new();
+            for i in 0..n.len() {
+                m.shl(8);
+                m.w[0] += n[i] as amcl::arch::Chunk;
+            }
+            m
+        }
+    };
+}
+
 pub fn big_to_bytes(n: &BIG, i_len: usize) -> Vec<u8> {
     let mut bytes = vec![];
     let mut n_copy = BIG::new_copy(n);
@@ -31,7 +46,7 @@ pub fn big_to_bytes(n: &BIG, i_len: usize) -> Vec<u8> {
 #[test]
 fn test_big_from_int() {
     let b = big_from_int(5);
-    let bs = i2osp(&b, 3);
-    let expected_bs : &[u8] = &[5, 0, 0];
+    let bs = big_to_bytes(&b, 3);
+    let expected_bs : &[u8] = &[0, 0, 5];
     assert!(expected_bs == bs.as_slice());
 }
@@ -0,0 +1,226 @@
+// constant-time helpers for comparisons and reductions that involve secrets, so that their
+// running time doesn't depend on the values
+
+use subtle::ConstantTimeEq;
+
+// the lengths are public, only the contents are compared in constant time
+pub fn bytes_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
+
+// the helpers on the BIG of a curve, for the legacy curve below and the VOPRF groups
+macro_rules! ct_big {
+    ($name:ident, $curve:ident) => {
+        pub mod $name {
+            use amcl::$curve::big::{self, BIG};
+            use amcl::arch::{self, Chunk};
+
+            use subtle::{Choice, ConstantTimeEq};
+
+            pub fn big_is_zero(a: &BIG) -> Choice {
+                let mut n = BIG::new_copy(a);
+                n.norm();
+                let mut acc : Chunk = 0;
+                for w in n.w.iter() {
+                    acc |= *w;
+                }
+                (acc as u64).ct_eq(&0)
+            }
+
+            pub fn big_eq(a: &BIG, b: &BIG) -> bool {
+                let mut a = BIG::new_copy(a);
+                let mut b = BIG::new_copy(b);
+                a.norm();
+                b.norm();
+                let mut acc : Chunk = 0;
+                for i in 0..big::NLEN {
+                    acc |= a.w[i] ^ b.w[i];
+                }
+                bool::from((acc as u64).ct_eq(&0))
+            }
+
+            // t mod m for t in [0, 2m). t - m is always computed, and t is moved back in if it went negative
+            pub fn reduce_once(t: &BIG, m: &BIG) -> BIG {
+                let mut r = BIG::new_copy(t);
+                r.sub(m);
+                r.norm();
+                // after norm, the sign of the value is the sign of the top word
+                let negative = (r.w[big::NLEN - 1] >> (arch::CHUNK - 1)) & 1;
+                r.cmove(t, negative as isize);
+                r
+            }
+
+            // (a - b) mod m, for a and b in [0, m]
+            pub fn sub_mod(a: &BIG, b: &BIG, m: &BIG) -> BIG {
+                let mut t = BIG::new_copy(m);
+                t.sub(b);
+                t.add(a);
+                t.norm();
+                reduce_once(&t, m)
+            }
+        }
+    };
+}
+
+ct_big!(p256, nist256);
+ct_big!(p384, nist384);
+#[cfg(feature = "p521")]
+ct_big!(p521, nist521);
+
+// the legacy protocol's curve
+pub use self::p256::{big_is_zero, big_eq, reduce_once, sub_mod};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{converters, random};
+    use crate::group::{Group, P256, P384};
+    use crate::types::curve::big::BIG;
+    use crate::types::curve::rom;
+    use rand::Rng;
+
+    use std::cmp::Ordering;
+    use std::time::Instant;
+
+    fn order() -> BIG {
+        BIG::new_ints(&rom::CURVE_ORDER)
+    }
+
+    #[test]
+    fn test_bytes_eq() {
+        assert!(bytes_eq(b"binding", b"binding"));
+        assert!(!bytes_eq(b"binding", b"bindinG"));
+        assert!(!bytes_eq(b"binding", b"bindin"));
+        assert!(bytes_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_sub_mod() {
+        let mut rng = rand::thread_rng();
+        let order = order();
+        for _ in 0..100 {
+            let a = random::rand_scalar_from_rng(&mut rng);
+            let b = random::rand_scalar_from_rng(&mut rng);
+            let r = sub_mod(&a, &b, &order);
+
+            let mut expected = BIG::new_copy(&a);
+            expected.add(&order);
+            expected.sub(&b);
+            expected.norm();
+            expected.rmod(&order);
+            assert!(r.cmp(&expected) == Ordering::Equal);
+            assert!(big_eq(&r, &expected));
+        }
+
+        let one = converters::big_from_int(1);
+        assert!(bool::from(big_is_zero(&sub_mod(&one, &one, &order))));
+        assert!(!bool::from(big_is_zero(&one)));
+        assert!(!big_eq(&one, &converters::big_from_int(2)));
+    }
+
+    // Welch's t statistic of the running times of f on two classes of inputs, measured in
+    // random order as in dudect. |t| above 4.5 is a sign that the time depends on the class
+    fn timing_t_statistic<F: FnMut(bool)>(samples: usize, mut f: F) -> f64 {
+        let mut rng = rand::thread_rng();
+        let mut stats = [(0f64, 0f64, 0f64); 2];
+        for _ in 0..samples {
+            let class : bool = rng.gen();
+            let start = Instant::now();
+            f(class);
+            let elapsed = start.elapsed();
+            let t = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+
+            // Welford's online mean and variance
+            let s = &mut stats[class as usize];
+            s.0 += 1.0;
+            let delta = t - s.1;
+            s.1 += delta / s.0;
+            s.2 += delta * (t - s.1);
+        }
+
+        let var = |s: &(f64, f64, f64)| s.2 / (s.0 - 1.0);
+        (stats[0].1 - stats[1].1) / (var(&stats[0]) / stats[0].0 + var(&stats[1]) / stats[1].0).sqrt()
+    }
+
+    // timing tests are noisy, so they only run on request:
+    // cargo test --release -- --ignored test_constant_time
+    #[test]
+    #[ignore]
+    fn test_constant_time_bytes_eq() {
+        let mut rng = rand::thread_rng();
+        let a = random::new_rand_vec(32, &mut rng);
+        let mut differs_early = a.clone();
+        differs_early[0] ^= 1;
+
+        let t = timing_t_statistic(1_000_000, |class| {
+            let b = if class { &a } else { &differs_early };
+            assert!(bytes_eq(&a, b) == class);
+        });
+        println!("bytes_eq: t = {}", t);
+        assert!(t.abs() < 4.5);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_constant_time_sub_mod() {
+        let order = order();
+        let small = converters::big_from_int(1);
+        let mut large = BIG::new_copy(&order);
+        large.sub(&small);
+        large.norm();
+
+        // the classes differ in whether a - b wraps around
+        let t = timing_t_statistic(1_000_000, |class| {
+            let (a, b) = if class { (&large, &small) } else { (&small, &large) };
+            sub_mod(a, b, &order);
+        });
+        println!("sub_mod: t = {}", t);
+        assert!(t.abs() < 4.5);
+    }
+
+    // the classes differ in whether r - c*k wraps around, as in the proofs of voprf
+    fn check_constant_time_scalar_sub<G: Group>(name: &str, small: G::Scalar, large: G::Scalar) {
+        let t = timing_t_statistic(1_000_000, |class| {
+            let (a, b) = if class { (&large, &small) } else { (&small, &large) };
+            G::scalar_sub(a, b);
+        });
+        println!("{} scalar_sub: t = {}", name, t);
+        assert!(t.abs() < 4.5);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_constant_time_scalar_sub() {
+        let order = order();
+        let small = converters::big_from_int(1);
+        let mut large = BIG::new_copy(&order);
+        large.sub(&small);
+        large.norm();
+        check_constant_time_scalar_sub::<P256>("P256", small, large);
+
+        let order = amcl::nist384::big::BIG::new_ints(&amcl::nist384::rom::CURVE_ORDER);
+        let small = amcl::nist384::big::BIG::new_int(1);
+        let mut large = amcl::nist384::big::BIG::new_copy(&order);
+        large.sub(&small);
+        large.norm();
+        check_constant_time_scalar_sub::<P384>("P384", small, large);
+    }
+
+    // scalar_eq checks the challenges of proofs, and h2c compares field elements with big_eq
+    #[test]
+    #[ignore]
+    fn test_constant_time_scalar_eq() {
+        let mut rng = rand::thread_rng();
+        let a = random::rand_scalar_from_rng(&mut rng);
+        // differs in the top word, where a variable-time comparison would stop
+        let mut differs_early = BIG::new_copy(&a);
+        differs_early.w[crate::types::curve::big::NLEN - 1] ^= 1;
+
+        let t = timing_t_statistic(1_000_000, |class| {
+            let b = if class { &a } else { &differs_early };
+            assert!(P256::scalar_eq(&a, b) == class);
+        });
+        println!("scalar_eq: t = {}", t);
+        assert!(t.abs() < 4.5);
+    }
+}
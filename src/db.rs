@@ -1,144 +1,297 @@
-use super::{types, ecc};
+use super::{types, ecc, hashes};
+use super::error::{Error, Result};
 
-use std::io::Cursor;
+use std::io::{Cursor, Read};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::error::Error;
-
-use rocksdb::DB;
+use rocksdb::{DB, Direction, IteratorMode, WriteBatch};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[derive(Clone)]
+pub struct StoredToken {
+    pub token: Vec<u8>,
+    pub signed_token: types::curve::ecp::ECP,
+    // None for tokens stored before issuer keys had ids
+    pub key_id: Option<String>,
+}
+
+impl StoredToken {
+    // token length (u32 LE), token, compressed point, then optionally key id length (u32 LE) and key id
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut val = vec![];
+        val.write_u32::<LittleEndian>(self.token.len() as u32)?;
+        val.extend_from_slice(&self.token);
+        val.extend_from_slice(&ecc::ecp_to_bytes(&self.signed_token, true));
+        if let Some(ref key_id) = self.key_id {
+            val.write_u32::<LittleEndian>(key_id.len() as u32)?;
+            val.extend_from_slice(key_id.as_bytes());
+        }
+
+        Ok(val)
+    }
+
+    fn from_bytes(stored_token_bytes: &[u8]) -> Result<StoredToken> {
+        let mut rdr = Cursor::new(stored_token_bytes);
+        let token_length = rdr.read_u32::<LittleEndian>()? as usize;
+        if token_length > stored_token_bytes.len() {
+            return Err(Error::CorruptRecord("token length out of bounds".to_string()));
+        }
+        let mut token = vec![0; token_length];
+        rdr.read_exact(&mut token)?;
+
+        let mut point_bytes = vec![0; types::curve::big::MODBYTES + 1];
+        rdr.read_exact(&mut point_bytes)?;
+        let signed_token = ecc::ecp_from_bytes(&point_bytes)?;
+
+        let key_id = if (rdr.position() as usize) < stored_token_bytes.len() {
+            let key_id_length = rdr.read_u32::<LittleEndian>()? as usize;
+            if key_id_length > stored_token_bytes.len() {
+                return Err(Error::CorruptRecord("key id length out of bounds".to_string()));
+            }
+            let mut key_id = vec![0; key_id_length];
+            rdr.read_exact(&mut key_id)?;
+            Some(String::from_utf8(key_id)?)
+        } else {
+            None
+        };
+
+        Ok(StoredToken {
+            token: token,
+            signed_token: signed_token,
+            key_id: key_id,
+        })
+    }
+}
+
+// a token held for a redemption in flight, until it is committed or released
+pub struct ReservedToken {
+    pub num: i64,
+    pub token: StoredToken,
+}
+
 pub struct DAL {
     pub db: DB,
+    // serializes the check-and-set in store_spent across server threads
+    spent_lock: Mutex<()>,
 }
 
+// the layout of the database, absent for databases written before it was versioned
+const VERSION_KEY: &str = "db_version";
+const VERSION: u32 = 1;
 const CURRENT_TOKEN_KEY: &str = "current_token";
 const FREE_TOKEN_KEY: &str = "free_token";
 const TOKEN_KEY_PREFIX: &str = "token_";
+// reserved tokens are stored as pending/<token num> -> lease expiry (u64 LE seconds)
+const PENDING_KEY_PREFIX: &str = "pending/";
+// spent tokens are stored as spent/<key id>/<hex of token hash> -> spend time (u64 LE seconds)
+const SPENT_KEY_PREFIX: &str = "spent/";
 
 impl DAL {
-    pub fn new(db_path: &str) -> Result<DAL, Box<Error>> {
+    pub fn new(db_path: &str) -> Result<DAL> {
         let dal = DAL {
             db: DB::open_default(db_path)?,
+            spent_lock: Mutex::new(()),
         };
+        dal.migrate()?;
         Ok(dal)
     }
 
-    pub fn add_token(&mut self, token: &[u8], signed_token: &types::curve::ecp::ECP) -> Result<(), Box<Error>> {
-        let next_token_num = self.get_next_free_token()?;
-        let next_token_num = next_token_num + 1;
+    fn migrate(&self) -> Result<()> {
+        let version = match self.db.get(VERSION_KEY.as_bytes())? {
+            Some(val) => Cursor::new(&*val).read_u32::<LittleEndian>()?,
+            None => 0,
+        };
+        if version >= VERSION {
+            return Ok(());
+        }
 
-        let point_bytes_len = types::curve::big::MODBYTES + 1;
-        let mut point_bytes = vec![0; point_bytes_len];
-        signed_token.tobytes(&mut point_bytes, true);
+        // version 0 databases have free_token pointing at the last stored token rather than
+        // past it, so that token was never handed out
+        let next_token_num = self.get_next_free_token()?;
+        let token_key = format!("{}{}", TOKEN_KEY_PREFIX, next_token_num);
+        if self.db.get(token_key.as_bytes())?.is_some() {
+            self.set_next_free_token(next_token_num + 1)?;
+        }
 
         let mut val = vec![];
-        val.write_u32::<LittleEndian>(token.len() as u32)?;
-        val.extend_from_slice(token);
-        val.extend_from_slice(&point_bytes);
+        val.write_u32::<LittleEndian>(VERSION)?;
+        self.db.put(VERSION_KEY.as_bytes(), &val)?;
+        Ok(())
+    }
+
+    pub fn add_token(&mut self, token: &[u8], signed_token: &types::curve::ecp::ECP, key_id: &str) -> Result<()> {
+        let next_token_num = self.get_next_free_token()?;
+
+        let stored_token = StoredToken {
+            token: token.to_vec(),
+            signed_token: *signed_token,
+            key_id: Some(key_id.to_string()),
+        };
 
         let next_token_key = format!("{}{}", TOKEN_KEY_PREFIX, next_token_num);
-        self.db.put(next_token_key.as_bytes(), &val)?;
+        self.db.put(next_token_key.as_bytes(), &stored_token.to_bytes()?)?;
 
         self.inc_next_free_token()?;
 
         Ok(())
     }
 
-    pub fn get_tokens(&self) -> Result<Vec<(Vec<u8>,types::curve::ecp::ECP)>, Box<Error>> {
+    // every token not yet committed, including reserved ones
+    pub fn get_tokens(&self) -> Result<Vec<StoredToken>> {
         let current_token_num = self.get_current_token()?;
         let next_token_num = self.get_next_free_token()?;
-        if current_token_num == next_token_num {
-            return Err("not enough tokens.".into());
-        }
+        debug!("current_token_num: {}, next_token_num: {}", current_token_num, next_token_num);
 
         let mut tokens = vec![];
-
-        debug!("current_token_num: {}, next_token_num: {}", current_token_num, next_token_num);
         for i in current_token_num..next_token_num {
-            let current_token_key = format!("{}{}", TOKEN_KEY_PREFIX, i);
-            let stored_token_bytes : &[u8] = &*self.db.get(current_token_key.as_bytes())?.unwrap();
-
-            let mut pos : usize = 0;
+            if let Some(token) = self.find_token(i)? {
+                tokens.push(token);
+            }
+        }
+        if tokens.is_empty() {
+            return Err(Error::NotEnoughTokens);
+        }
 
-            let token_length_bytes = &stored_token_bytes[..4];
-            let mut rdr = Cursor::new(token_length_bytes);
-            let token_length = rdr.read_u32::<LittleEndian>()?;
-            pos += 4;
+        Ok(tokens)
+    }
 
-            let token = &stored_token_bytes[pos..pos+(token_length as usize)];
-            pos += token_length as usize;
+    // how many tokens reserve_token can still return
+    pub fn count_tokens(&self) -> Result<usize> {
+        let now = now();
+        let mut count = 0;
+        for i in self.get_current_token()?..self.get_next_free_token()? {
+            if self.find_token(i)?.is_some() && !self.is_leased(i, now)? {
+                count += 1;
+            }
+        }
 
-            let ecp_length = types::curve::big::MODBYTES + 1;
-            let p = ecc::ecp_from_bytes(&stored_token_bytes[pos..pos+(ecp_length as usize)])?;
+        Ok(count)
+    }
 
-            tokens.push((token.to_vec(), p));
+    // holds the next available token for lease_secs. it stays stored until commit_token, and
+    // is available again after release_token or once the lease expires, e.g. after a crash
+    pub fn reserve_token(&mut self, lease_secs: u64) -> Result<ReservedToken> {
+        let now = now();
+        for i in self.get_current_token()?..self.get_next_free_token()? {
+            let token = match self.find_token(i)? {
+                Some(token) => token,
+                None => continue,
+            };
+            if self.is_leased(i, now)? {
+                continue;
+            }
+
+            let mut val = vec![];
+            val.write_u64::<LittleEndian>(now.saturating_add(lease_secs))?;
+            self.db.put(DAL::pending_key(i).as_bytes(), &val)?;
+
+            return Ok(ReservedToken {
+                num: i,
+                token: token,
+            });
         }
 
-        Ok(tokens)
+        Err(Error::NotEnoughTokens)
     }
 
-    pub fn pop_next_token(&mut self) -> Result<(Vec<u8>,types::curve::ecp::ECP), Box<Error>> {
-        let current_token_num = self.get_current_token()?;
+    // the issuer accepted the token, so it's removed for good
+    pub fn commit_token(&mut self, num: i64) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(format!("{}{}", TOKEN_KEY_PREFIX, num).as_bytes())?;
+        batch.delete(DAL::pending_key(num).as_bytes())?;
+        self.db.write(batch)?;
+
+        // skip over the tokens committed so far
         let next_token_num = self.get_next_free_token()?;
-        if current_token_num == next_token_num {
-            return Err("not enough tokens.".into());
+        let mut current_token_num = self.get_current_token()?;
+        while current_token_num < next_token_num && self.find_token(current_token_num)?.is_none() {
+            current_token_num = self.inc_current_token()?;
         }
 
-        let current_token_key = format!("{}{}", TOKEN_KEY_PREFIX, current_token_num);
-        let stored_token_bytes : &[u8] = &*self.db.get(current_token_key.as_bytes())?.unwrap();
+        Ok(())
+    }
+
+    // the redemption didn't go through, so the token can be used again
+    pub fn release_token(&mut self, num: i64) -> Result<()> {
+        self.db.delete(DAL::pending_key(num).as_bytes())?;
+        Ok(())
+    }
+
+    // releases every reservation, live or not. only for when no redemption can be in flight,
+    // such as at startup, returning how many were released
+    pub fn recover_pending(&mut self) -> Result<usize> {
+        let mut batch = WriteBatch::default();
+        let mut released = 0;
+        let iter = self.db.iterator(IteratorMode::From(PENDING_KEY_PREFIX.as_bytes(), Direction::Forward));
+        for (key, _) in iter {
+            if !key.starts_with(PENDING_KEY_PREFIX.as_bytes()) {
+                break;
+            }
+            batch.delete(&key)?;
+            released += 1;
+        }
+        self.db.write(batch)?;
 
-        let mut pos : usize = 0;
+        Ok(released)
+    }
 
-        let token_length_bytes = &stored_token_bytes[..4];
-        let mut rdr = Cursor::new(token_length_bytes);
-        let token_length = rdr.read_u32::<LittleEndian>()?;
-        pos += 4;
+    fn pending_key(token_num: i64) -> String {
+        format!("{}{}", PENDING_KEY_PREFIX, token_num)
+    }
 
-        let token = &stored_token_bytes[pos..pos+(token_length as usize)];
-        pos += token_length as usize;
+    fn is_leased(&self, token_num: i64, now: u64) -> Result<bool> {
+        match self.db.get(DAL::pending_key(token_num).as_bytes())? {
+            Some(val) => Ok(Cursor::new(&*val).read_u64::<LittleEndian>()? > now),
+            None => Ok(false),
+        }
+    }
 
-        let ecp_length = types::curve::big::MODBYTES + 1;
-        let p = ecc::ecp_from_bytes(&stored_token_bytes[pos..pos+(ecp_length as usize)])?;
+    fn find_token(&self, token_num: i64) -> Result<Option<StoredToken>> {
+        let token_key = format!("{}{}", TOKEN_KEY_PREFIX, token_num);
+        if self.db.get(token_key.as_bytes())?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.get_token(token_num)?))
+    }
 
-        self.inc_current_token()?;
+    fn get_token(&self, token_num: i64) -> Result<StoredToken> {
+        let token_key = format!("{}{}", TOKEN_KEY_PREFIX, token_num);
+        let stored_token_bytes = self.db.get(token_key.as_bytes())?
+            .ok_or_else(|| Error::CorruptRecord(token_key.clone()))?;
 
-        Ok((token.to_vec(), p))
+        StoredToken::from_bytes(&*stored_token_bytes)
+            .map_err(|_| Error::CorruptRecord(token_key))
     }
 
-    fn get_current_token(&self) -> Result<i64, Box<Error>> {
-        let current_token_num_db = self.db.get(CURRENT_TOKEN_KEY.as_bytes())?;
-        let current_token_num_db : Result<_, Box<Error>> = match current_token_num_db {
-            Some(s) => Ok(s),
-            None => Err("current token num is undefined.".into()),
-        };
-        if current_token_num_db.is_err() {
-            return Ok(0);
+    fn get_current_token(&self) -> Result<i64> {
+        let current_token_num_db = match self.db.get(CURRENT_TOKEN_KEY.as_bytes())? {
+            Some(s) => s,
+            None => return Ok(0),
         };
 
-        let current_token_num_db = current_token_num_db.unwrap();
         let mut rdr = Cursor::new(&*current_token_num_db);
         let current_token = rdr.read_u32::<LittleEndian>()?;
         Ok(current_token as i64)
     }
 
-    fn get_next_free_token(&self) -> Result<i64, Box<Error>> {
-        let next_token_num_db = self.db.get(FREE_TOKEN_KEY.as_bytes())?;
-        let next_token_num_db : Result<_, Box<Error>> = match next_token_num_db {
-            Some(s) => Ok(s),
-            None => Err("next token num is undefined.".into()),
-        };
-        if next_token_num_db.is_err() {
-            return Ok(-1);
+    fn get_next_free_token(&self) -> Result<i64> {
+        let next_token_num_db = match self.db.get(FREE_TOKEN_KEY.as_bytes())? {
+            Some(s) => s,
+            None => return Ok(0),
         };
 
-        let next_token_num_db = next_token_num_db.unwrap();
         let mut rdr = Cursor::new(&*next_token_num_db);
         let next_token = rdr.read_u32::<LittleEndian>()?;
         Ok(next_token as i64)
     }
 
-    fn inc_current_token(&mut self) -> Result<i64, Box<Error>> {
+    fn inc_current_token(&mut self) -> Result<i64> {
         let current_token = self.get_current_token()?;
         let current_token_num = current_token + 1;
         let mut current_token_inc = vec![];
@@ -147,22 +300,203 @@ impl DAL {
         Ok(current_token_num as i64)
     }
 
-    fn inc_next_free_token(&mut self) -> Result<u32, Box<Error>> {
+    fn inc_next_free_token(&mut self) -> Result<u32> {
         let next_token = self.get_next_free_token()?;
         let next_token_num = next_token + 1;
+        self.set_next_free_token(next_token_num)?;
+        Ok(next_token_num as u32)
+    }
+
+    fn set_next_free_token(&self, next_token_num: i64) -> Result<()> {
         let mut next_token_inc = vec![];
         next_token_inc.write_u32::<LittleEndian>(next_token_num as u32)?;
         self.db.put(FREE_TOKEN_KEY.as_bytes(), &next_token_inc)?;
-        Ok(next_token_num as u32)
+        Ok(())
+    }
+
+    fn spent_key(key_id: &str, token: &[u8]) -> String {
+        format!("{}{}/{}", SPENT_KEY_PREFIX, key_id, hex::encode(&hashes::hash_token(token)))
     }
 
-    pub fn store_spent(&mut self, token: &[u8]) -> Result<(), Box<Error>> {
-        let stored_token_bytes_db = self.db.get(token)?;
-        if !stored_token_bytes_db.is_none() {
-            return Err("token already spent.".into());
+    pub fn store_spent(&self, key_id: &str, token: &[u8]) -> Result<()> {
+        let _guard = self.spent_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let spent_key = DAL::spent_key(key_id, token);
+        if self.db.get(spent_key.as_bytes())?.is_some() {
+            return Err(Error::DoubleSpend);
+        }
+        // tokens spent before spent records were scoped by key were stored under their raw bytes
+        if self.db.get(token)?.is_some() {
+            return Err(Error::DoubleSpend);
         }
 
-        self.db.put(token, &[1])?;
+        let now = now();
+        let mut val = vec![];
+        val.write_u64::<LittleEndian>(now)?;
+        self.db.put(spent_key.as_bytes(), &val)?;
         Ok(())
     }
+
+    pub fn get_spent_time(&self, key_id: &str, token: &[u8]) -> Result<Option<u64>> {
+        match self.db.get(DAL::spent_key(key_id, token).as_bytes())? {
+            Some(val) => Ok(Some(Cursor::new(&*val).read_u64::<LittleEndian>()?)),
+            None => Ok(None),
+        }
+    }
+
+    // drops the spent records of every key not in active_key_ids, returning how many were removed
+    pub fn prune_spent(&self, active_key_ids: &[&str]) -> Result<usize> {
+        let _guard = self.spent_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut batch = WriteBatch::default();
+        let mut pruned = 0;
+        let iter = self.db.iterator(IteratorMode::From(SPENT_KEY_PREFIX.as_bytes(), Direction::Forward));
+        for (key, _) in iter {
+            if !key.starts_with(SPENT_KEY_PREFIX.as_bytes()) {
+                break;
+            }
+            let key_id = key[SPENT_KEY_PREFIX.len()..].split(|b| *b == b'/').next().unwrap_or(&[]);
+            if !active_key_ids.iter().any(|id| id.as_bytes() == key_id) {
+                batch.delete(&key)?;
+                pruned += 1;
+            }
+        }
+        self.db.write(batch)?;
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dal(name: &str) -> DAL {
+        let mut path = std::env::temp_dir();
+        path.push(format!("privacypass-rs-db-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        DAL::new(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_store_spent() {
+        let dal = temp_dal("spent");
+        dal.store_spent("key1", b"token").unwrap();
+        assert!(dal.get_spent_time("key1", b"token").unwrap().is_some());
+        assert!(matches!(dal.store_spent("key1", b"token"), Err(Error::DoubleSpend)));
+
+        // spent records are scoped by key
+        dal.store_spent("key2", b"token").unwrap();
+
+        // records from before key scoping are still honored
+        dal.db.put(b"legacy token", &[1]).unwrap();
+        assert!(dal.store_spent("key1", b"legacy token").is_err());
+    }
+
+    #[test]
+    fn test_prune_spent() {
+        let dal = temp_dal("prune");
+        dal.store_spent("retired", b"token1").unwrap();
+        dal.store_spent("retired", b"token2").unwrap();
+        dal.store_spent("active", b"token3").unwrap();
+        dal.db.put(b"token_0", &[1]).unwrap();
+
+        assert!(dal.prune_spent(&["active"]).unwrap() == 2);
+        assert!(dal.get_spent_time("retired", b"token1").unwrap().is_none());
+        assert!(dal.get_spent_time("active", b"token3").unwrap().is_some());
+        assert!(dal.db.get(b"token_0").unwrap().is_some());
+        assert!(dal.prune_spent(&["active"]).unwrap() == 0);
+    }
+
+    fn add_tokens(dal: &mut DAL, n: u8) {
+        let p = hashes::hash_to_curve(b"stored token").unwrap();
+        for i in 0..n {
+            dal.add_token(&[i], &p, "key").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reserve_commit_release() {
+        let mut dal = temp_dal("reserve");
+        assert!(dal.count_tokens().unwrap() == 0);
+        assert!(dal.reserve_token(60).is_err());
+
+        add_tokens(&mut dal, 3);
+        assert!(dal.count_tokens().unwrap() == 3);
+        assert!(dal.get_tokens().unwrap().len() == 3);
+
+        let first = dal.reserve_token(60).unwrap();
+        let second = dal.reserve_token(60).unwrap();
+        assert!(first.token.token == [0]);
+        assert!(second.token.token == [1]);
+        assert!(dal.count_tokens().unwrap() == 1);
+
+        // released tokens are handed out again, committed ones never are
+        dal.release_token(first.num).unwrap();
+        dal.commit_token(second.num).unwrap();
+        assert!(dal.count_tokens().unwrap() == 2);
+        assert!(dal.reserve_token(60).unwrap().token.token == [0]);
+        assert!(dal.reserve_token(60).unwrap().token.token == [2]);
+        assert!(dal.reserve_token(60).is_err());
+        assert!(dal.get_tokens().unwrap().len() == 2);
+    }
+
+    #[test]
+    fn test_recover_pending() {
+        let mut dal = temp_dal("recover");
+        add_tokens(&mut dal, 2);
+
+        // an expired lease, as left by a crash, doesn't hold the token
+        let expired = dal.reserve_token(0).unwrap();
+        assert!(dal.count_tokens().unwrap() == 2);
+        assert!(dal.reserve_token(60).unwrap().num == expired.num);
+
+        dal.reserve_token(60).unwrap();
+        assert!(dal.count_tokens().unwrap() == 0);
+        assert!(dal.recover_pending().unwrap() == 2);
+        assert!(dal.count_tokens().unwrap() == 2);
+
+        // a lease too long to represent holds the token rather than wrapping around
+        dal.reserve_token(u64::max_value()).unwrap();
+        assert!(dal.count_tokens().unwrap() == 1);
+    }
+
+    // a client database from before the token store was versioned, written as that client did:
+    // records without key ids, and free_token holding the number of the last stored token
+    #[test]
+    fn test_free_token_migration() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("privacypass-rs-db-migration-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        {
+            let db = DB::open_default(path.to_str().unwrap()).unwrap();
+            let p = hashes::hash_to_curve(b"stored token").unwrap();
+            for i in 0..2u8 {
+                let mut val = vec![];
+                val.write_u32::<LittleEndian>(1).unwrap();
+                val.push(i);
+                val.extend_from_slice(&ecc::ecp_to_bytes(&p, true));
+                db.put(format!("token_{}", i).as_bytes(), &val).unwrap();
+            }
+            let mut free = vec![];
+            free.write_u32::<LittleEndian>(1).unwrap();
+            db.put(b"free_token", &free).unwrap();
+        }
+
+        // the old client only ever saw token 0
+        let mut dal = DAL::new(path.to_str().unwrap()).unwrap();
+        assert!(dal.count_tokens().unwrap() == 2);
+        let tokens = dal.get_tokens().unwrap();
+        assert!(tokens[0].token == [0] && tokens[1].token == [1]);
+        assert!(tokens[1].key_id.is_none());
+        add_tokens(&mut dal, 1);
+        assert!(dal.count_tokens().unwrap() == 3);
+        drop(dal);
+
+        // the migration runs once, so reopening doesn't move free_token again
+        let dal = DAL::new(path.to_str().unwrap()).unwrap();
+        let val = dal.db.get(b"token_0").unwrap().unwrap();
+        dal.db.put(b"token_3", &*val).unwrap();
+        drop(dal);
+        let dal = DAL::new(path.to_str().unwrap()).unwrap();
+        assert!(dal.get_next_free_token().unwrap() == 3);
+    }
 }
@@ -1,12 +1,241 @@
+#![allow(non_snake_case)]
+
 use super::types;
+use super::types::curve::{big::{self, BIG}, ecp::ECP};
+use super::error::{Error, Result};
+
+use subtle::ConstantTimeEq;
 
-use std::error::Error;
+pub fn ecp_from_bytes(bytes: &[u8]) -> Result<types::curve::ecp::ECP> {
+    let modbytes = types::curve::big::MODBYTES;
+    let valid_encoding = match bytes.first() {
+        Some(0x02) | Some(0x03) => bytes.len() == modbytes + 1,
+        Some(0x04) => bytes.len() == 2*modbytes + 1,
+        _ => false,
+    };
+    if !valid_encoding {
+        return Err(Error::InvalidPoint);
+    }
 
-pub fn ecp_from_bytes(bytes: &[u8]) -> Result<types::curve::ecp::ECP, Box<Error>> {
+    // frombytes returns infinity for points that are not on the curve
     let p = types::curve::ecp::ECP::frombytes(&bytes);
     if !p.is_infinity() {
         return Ok(p);
     }
 
-    return Err("can't parse ecp".into());
+    return Err(Error::InvalidPoint);
+}
+
+pub fn ecp_to_bytes(p: &types::curve::ecp::ECP, compress: bool) -> Vec<u8> {
+    let modbytes = types::curve::big::MODBYTES;
+    let bytes_len = if compress { modbytes + 1 } else { 2*modbytes + 1 };
+    let mut bytes = vec![0; bytes_len];
+    p.tobytes(&mut bytes, compress);
+    bytes
+}
+
+const WINDOW_BITS: usize = 4;
+const WINDOW_SIZE: usize = 1 << WINDOW_BITS;
+
+// the multiples j * 16^i * P of a fixed point P, so that multiplying it by a scalar takes one
+// addition per 4 bits of the scalar and no doublings. every entry of a window is read and the
+// right one selected with cmove, so the memory accesses don't depend on the scalar
+pub struct FixedBaseTable {
+    windows: Vec<Vec<ECP>>,
+}
+
+impl FixedBaseTable {
+    pub fn new(P: &ECP) -> Self {
+        let num_windows = (8*big::MODBYTES + WINDOW_BITS - 1) / WINDOW_BITS;
+        let mut windows = Vec::with_capacity(num_windows);
+        let mut base = *P;
+        for _ in 0..num_windows {
+            let mut window = Vec::with_capacity(WINDOW_SIZE);
+            let mut multiple = ECP::new();
+            for _ in 0..WINDOW_SIZE {
+                window.push(multiple);
+                multiple.add(&base);
+            }
+            // 16 * base, the base of the next window
+            base = multiple;
+            windows.push(window);
+        }
+
+        FixedBaseTable { windows: windows }
+    }
+
+    // same as P.mul(e), for e of up to 8*MODBYTES bits
+    pub fn mul(&self, e: &BIG) -> ECP {
+        let mut e = BIG::new_copy(e);
+        e.norm();
+
+        let mut R = ECP::new();
+        for (i, window) in self.windows.iter().enumerate() {
+            let mut digit = 0;
+            for j in 0..WINDOW_BITS {
+                digit |= e.bit(i*WINDOW_BITS + j) << j;
+            }
+
+            let mut selected = ECP::new();
+            for (j, multiple) in window.iter().enumerate() {
+                selected.cmove(multiple, (j as u64).ct_eq(&(digit as u64)).unwrap_u8() as isize);
+            }
+            R.add(&selected);
+        }
+
+        R
+    }
+}
+
+// below this many points Straus' method is faster, above it Pippenger's
+const PIPPENGER_THRESHOLD: usize = 192;
+
+// the width bits of e starting at bit start, as a number
+fn window_digit(e: &BIG, start: usize, width: usize) -> usize {
+    let mut digit = 0;
+    for j in 0..width {
+        if start + j < 8*big::MODBYTES {
+            digit |= (e.bit(start + j) as usize) << j;
+        }
+    }
+    digit
+}
+
+// the sum of scalars[i] * points[i], as in the composites of a batch proof. the running time
+// depends on the scalars, so they must not be secret
+pub fn multi_mul(points: &[ECP], scalars: &[BIG]) -> ECP {
+    assert!(points.len() == scalars.len());
+    let scalars : Vec<BIG> = scalars.iter()
+        .map(|e| {
+            let mut e = BIG::new_copy(e);
+            e.norm();
+            e
+        })
+        .collect();
+
+    if points.len() < PIPPENGER_THRESHOLD {
+        straus(points, &scalars)
+    } else {
+        pippenger(points, &scalars)
+    }
+}
+
+// interleaved 4 bit windows: a table of 16 multiples per point, and the doublings are shared
+fn straus(points: &[ECP], scalars: &[BIG]) -> ECP {
+    let tables : Vec<Vec<ECP>> = points.iter()
+        .map(|P| {
+            let mut table = Vec::with_capacity(WINDOW_SIZE);
+            let mut multiple = ECP::new();
+            for _ in 0..WINDOW_SIZE {
+                table.push(multiple);
+                multiple.add(P);
+            }
+            table
+        })
+        .collect();
+
+    let num_windows = (8*big::MODBYTES + WINDOW_BITS - 1) / WINDOW_BITS;
+    let mut R = ECP::new();
+    for w in (0..num_windows).rev() {
+        for _ in 0..WINDOW_BITS {
+            R.dbl();
+        }
+        for (table, e) in tables.iter().zip(scalars.iter()) {
+            let digit = window_digit(e, w*WINDOW_BITS, WINDOW_BITS);
+            if digit != 0 {
+                R.add(&table[digit]);
+            }
+        }
+    }
+
+    R
+}
+
+// bucket method: every point is added once per window into the bucket of its digit, and the
+// buckets are summed with a running sum
+fn pippenger(points: &[ECP], scalars: &[BIG]) -> ECP {
+    let mut log_n = 0;
+    while (1 << (log_n + 1)) <= points.len() {
+        log_n += 1;
+    }
+    let width = if log_n > 6 { log_n - 2 } else { 4 };
+    let num_windows = (8*big::MODBYTES + width - 1) / width;
+
+    let mut R = ECP::new();
+    for w in (0..num_windows).rev() {
+        for _ in 0..width {
+            R.dbl();
+        }
+
+        let mut buckets = vec![ECP::new(); (1 << width) - 1];
+        for (P, e) in points.iter().zip(scalars.iter()) {
+            let digit = window_digit(e, w*width, width);
+            if digit != 0 {
+                buckets[digit - 1].add(P);
+            }
+        }
+
+        // sum of (i + 1) * buckets[i]
+        let mut running_sum = ECP::new();
+        let mut window_sum = ECP::new();
+        for bucket in buckets.iter().rev() {
+            running_sum.add(bucket);
+            window_sum.add(&running_sum);
+        }
+        R.add(&window_sum);
+    }
+
+    R
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{converters, hashes, random};
+
+    #[test]
+    fn test_fixed_base_table() {
+        let mut rng = rand::thread_rng();
+        let P = hashes::hash_to_curve(b"fixed base").unwrap();
+        let table = FixedBaseTable::new(&P);
+
+        for _ in 0..20 {
+            let e = random::rand_scalar_from_rng(&mut rng);
+            assert!(table.mul(&e) == P.mul(&e));
+        }
+        assert!(table.mul(&BIG::new()).is_infinity());
+        assert!(table.mul(&converters::big_from_int(1)) == P);
+    }
+
+    fn naive_multi_mul(points: &[ECP], scalars: &[BIG]) -> ECP {
+        let mut R = ECP::new();
+        for (P, e) in points.iter().zip(scalars.iter()) {
+            R.add(&P.mul(e));
+        }
+        R
+    }
+
+    #[test]
+    fn test_multi_mul() {
+        let mut rng = rand::thread_rng();
+        let points : Vec<ECP> = (0..300u32)
+            .map(|i| hashes::hash_to_curve(&i.to_be_bytes()).unwrap())
+            .collect();
+        let scalars : Vec<BIG> = (0..300)
+            .map(|_| random::rand_scalar_from_rng(&mut rng))
+            .collect();
+
+        assert!(multi_mul(&[], &[]).is_infinity());
+        for n in [1, 2, 17, 100].iter() {
+            let expected = naive_multi_mul(&points[..*n], &scalars[..*n]);
+            assert!(straus(&points[..*n], &scalars[..*n]) == expected);
+            assert!(pippenger(&points[..*n], &scalars[..*n]) == expected);
+        }
+        assert!(multi_mul(&points, &scalars) == naive_multi_mul(&points, &scalars));
+
+        // repeated points and small scalars land in the same buckets
+        let repeated = vec![points[0]; 4];
+        let small : Vec<BIG> = (1..5).map(converters::big_from_int).collect();
+        assert!(multi_mul(&repeated, &small) == points[0].mul(&converters::big_from_int(10)));
+    }
 }
@@ -0,0 +1,155 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+use config::ConfigError;
+
+#[derive(Debug)]
+pub enum Error {
+    // redemption
+    DoubleSpend,
+    RedemptionFailed(String),
+    InvalidMac,
+    InvalidToken,
+    InvalidSignature,
+    // issuance
+    InvalidProof,
+    TooManyTokens { requested: usize, max: usize },
+    EmptyRequest,
+    WrongTokenCount { requested: usize, received: usize },
+    IssueWorkerFailed,
+    // keys
+    CommitmentMismatch,
+    NoIssuingKey,
+    UnknownKey(String),
+    InvalidCommitment(String),
+    CommitmentExpired(String),
+    // encoding
+    InvalidPoint,
+    InvalidScalar,
+    InvalidInput(String),
+    HashToCurve,
+    UnknownRequest(String),
+    MalformedRequest(String),
+    UnsupportedTokenType(u16),
+    // transport
+    MessageTooLarge(usize),
+    Http(reqwest::Error),
+    // client token storage
+    NotEnoughTokens,
+    CorruptRecord(String),
+    // wrapped errors
+    Storage(rocksdb::Error),
+    Io(io::Error),
+    Json(serde_json::Error),
+    Base64(base64::DecodeError),
+    Utf8(FromUtf8Error),
+    Config(ConfigError),
+    Crypto(openssl::error::ErrorStack),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DoubleSpend => write!(f, "token already spent."),
+            Error::RedemptionFailed(m) => write!(f, "redemption failed: {}", m),
+            Error::InvalidMac => write!(f, "request binding is invalid."),
+            Error::InvalidToken => write!(f, "token authenticator is invalid."),
+            Error::InvalidSignature => write!(f, "signature is invalid."),
+            Error::InvalidProof => write!(f, "dleq proof is invalid."),
+            Error::TooManyTokens { requested, max } => write!(f, "too many tokens requested: {} > {}", requested, max),
+            Error::EmptyRequest => write!(f, "no tokens requested."),
+            Error::WrongTokenCount { requested, received } => write!(f, "issuer signed {} tokens, {} were requested", received, requested),
+            Error::IssueWorkerFailed => write!(f, "a worker signing the request failed."),
+            Error::CommitmentMismatch => write!(f, "commitment H doesn't match the secret key."),
+            Error::NoIssuingKey => write!(f, "no issuing key configured."),
+            Error::UnknownKey(id) => write!(f, "unknown key id: {}", id),
+            Error::InvalidCommitment(m) => write!(f, "invalid commitment: {}", m),
+            Error::CommitmentExpired(v) => write!(f, "commitment version {} has expired.", v),
+            Error::InvalidPoint => write!(f, "can't parse ecp"),
+            Error::InvalidScalar => write!(f, "can't parse scalar"),
+            Error::InvalidInput(m) => write!(f, "invalid input: {}", m),
+            Error::HashToCurve => write!(f, "hash to curve resulted in infinity"),
+            Error::UnknownRequest(t) => write!(f, "unknown request: {}", t),
+            Error::MalformedRequest(m) => write!(f, "malformed request: {}", m),
+            Error::UnsupportedTokenType(t) => write!(f, "unsupported token type: {:#06x}", t),
+            Error::MessageTooLarge(len) => write!(f, "message too large: {} bytes", len),
+            Error::Http(e) => write!(f, "http error: {}", e),
+            Error::NotEnoughTokens => write!(f, "not enough tokens."),
+            Error::CorruptRecord(m) => write!(f, "corrupt record: {}", m),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Json(e) => write!(f, "json error: {}", e),
+            Error::Base64(e) => write!(f, "base64 error: {}", e),
+            Error::Utf8(e) => write!(f, "utf8 error: {}", e),
+            Error::Config(e) => write!(f, "config error: {}", e),
+            Error::Crypto(e) => write!(f, "crypto error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Http(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Base64(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            Error::Config(e) => Some(e),
+            Error::Crypto(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rocksdb::Error> for Error {
+    fn from(e: rocksdb::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Base64(e)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::Utf8(e)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Error::Crypto(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
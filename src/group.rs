@@ -0,0 +1,365 @@
+// prime order groups the RFC 9497 VOPRF runs over. P-256 is always available since the legacy
+// protocol needs it, and P-384 since RFC 9578 type 0x0001 tokens do. P-521 and ristretto255 are
+// behind the features of the same names.
+
+use super::error::{Error, Result};
+use super::h2c;
+use super::random;
+
+use rand::Rng;
+
+pub trait Group {
+    type Scalar: Clone;
+    type Element: Clone;
+
+    // the RFC 9497 ciphersuite identifier, part of every domain separation tag
+    const SUITE_ID: &'static str;
+    // sizes of serialized elements and scalars
+    const NE: usize;
+    const NS: usize;
+
+    fn generator() -> Self::Element;
+    fn identity() -> Self::Element;
+    fn add(a: &Self::Element, b: &Self::Element) -> Self::Element;
+    fn mul(p: &Self::Element, s: &Self::Scalar) -> Self::Element;
+    fn element_eq(a: &Self::Element, b: &Self::Element) -> bool;
+
+    // arithmetic modulo the group order
+    fn scalar_mul(a: &Self::Scalar, b: &Self::Scalar) -> Self::Scalar;
+    fn scalar_sub(a: &Self::Scalar, b: &Self::Scalar) -> Self::Scalar;
+    fn scalar_invert(s: &Self::Scalar) -> Self::Scalar;
+    fn scalar_is_zero(s: &Self::Scalar) -> bool;
+    fn scalar_eq(a: &Self::Scalar, b: &Self::Scalar) -> bool;
+    // never zero
+    fn random_scalar<R: Rng>(rng: &mut R) -> Self::Scalar;
+
+    fn hash_to_group(input: &[u8], dst: &[u8]) -> Result<Self::Element>;
+    fn hash_to_scalar(input: &[u8], dst: &[u8]) -> Result<Self::Scalar>;
+    // the hash function of the ciphersuite
+    fn hash(input: &[u8]) -> Vec<u8>;
+
+    fn serialize_element(p: &Self::Element) -> Vec<u8>;
+    // rejects the identity as well as non-canonical encodings
+    fn deserialize_element(bytes: &[u8]) -> Result<Self::Element>;
+    fn serialize_scalar(s: &Self::Scalar) -> Vec<u8>;
+    fn deserialize_scalar(bytes: &[u8]) -> Result<Self::Scalar>;
+}
+
+// a NIST curve from amcl, with elements in compressed SEC1 form. the h2c module of the same
+// name provides the hash-to-curve suite
+macro_rules! nist_group {
+    ($name:ident, $group:ident, $curve:ident, $suite_id:expr) => {
+        mod $name {
+            use super::*;
+            use amcl::$curve::{big::{self, BIG}, ecp::ECP, rom};
+
+            use std::cmp::Ordering;
+
+            fn order() -> BIG {
+                BIG::new_ints(&rom::CURVE_ORDER)
+            }
+
+            pub struct $group;
+
+            impl Group for $group {
+                type Scalar = BIG;
+                type Element = ECP;
+
+                const SUITE_ID: &'static str = $suite_id;
+                const NE: usize = big::MODBYTES + 1;
+                const NS: usize = big::MODBYTES;
+
+                fn generator() -> ECP {
+                    ECP::new_bigs(&BIG::new_ints(&rom::CURVE_GX), &BIG::new_ints(&rom::CURVE_GY))
+                }
+
+                fn identity() -> ECP {
+                    ECP::new()
+                }
+
+                fn add(a: &ECP, b: &ECP) -> ECP {
+                    let mut r = *a;
+                    r.add(b);
+                    r
+                }
+
+                fn mul(p: &ECP, s: &BIG) -> ECP {
+                    p.mul(s)
+                }
+
+                fn element_eq(a: &ECP, b: &ECP) -> bool {
+                    a == b
+                }
+
+                fn scalar_mul(a: &BIG, b: &BIG) -> BIG {
+                    BIG::modmul(a, b, &order())
+                }
+
+                // (a - b) mod n, for a and b already reduced. constant time, as it computes
+                // the s of a proof from the secret key
+                fn scalar_sub(a: &BIG, b: &BIG) -> BIG {
+                    crate::ct::$name::sub_mod(a, b, &order())
+                }
+
+                fn scalar_invert(s: &BIG) -> BIG {
+                    let mut r = BIG::new_copy(s);
+                    r.invmodp(&order());
+                    r
+                }
+
+                fn scalar_is_zero(s: &BIG) -> bool {
+                    bool::from(crate::ct::$name::big_is_zero(s))
+                }
+
+                fn scalar_eq(a: &BIG, b: &BIG) -> bool {
+                    crate::ct::$name::big_eq(a, b)
+                }
+
+                // 16 extra bytes make the bias of the reduction negligible
+                fn random_scalar<R: Rng>(rng: &mut R) -> BIG {
+                    loop {
+                        let bytes = random::new_rand_vec(Self::NS + 16, rng);
+                        let s = h2c::$name::dbig_from_bytes(&bytes).dmod(&order());
+                        if !s.iszilch() {
+                            return s;
+                        }
+                    }
+                }
+
+                fn hash_to_group(input: &[u8], dst: &[u8]) -> Result<ECP> {
+                    h2c::$name::hash_to_curve(input, dst)
+                }
+
+                fn hash_to_scalar(input: &[u8], dst: &[u8]) -> Result<BIG> {
+                    let mut scalars = h2c::$name::hash_to_field(input, dst, 1, &order())?;
+                    Ok(scalars.remove(0))
+                }
+
+                fn hash(input: &[u8]) -> Vec<u8> {
+                    h2c::$name::XMD.hash(&[input])
+                }
+
+                fn serialize_element(p: &ECP) -> Vec<u8> {
+                    let mut bytes = vec![0; Self::NE];
+                    p.tobytes(&mut bytes, true);
+                    bytes
+                }
+
+                fn deserialize_element(bytes: &[u8]) -> Result<ECP> {
+                    match bytes.first() {
+                        Some(0x02) | Some(0x03) if bytes.len() == Self::NE => {},
+                        _ => return Err(Error::InvalidPoint),
+                    }
+                    // frombytes returns infinity for points that are not on the curve
+                    let p = ECP::frombytes(bytes);
+                    if p.is_infinity() {
+                        return Err(Error::InvalidPoint);
+                    }
+                    Ok(p)
+                }
+
+                fn serialize_scalar(s: &BIG) -> Vec<u8> {
+                    let mut bytes = vec![0; Self::NS];
+                    s.tobytes(&mut bytes);
+                    bytes
+                }
+
+                fn deserialize_scalar(bytes: &[u8]) -> Result<BIG> {
+                    if bytes.len() != Self::NS {
+                        return Err(Error::InvalidScalar);
+                    }
+                    let s = BIG::frombytes(bytes);
+                    if s.cmp(&order()) != Ordering::Less {
+                        return Err(Error::InvalidScalar);
+                    }
+                    Ok(s)
+                }
+            }
+        }
+
+        pub use self::$name::$group;
+    };
+}
+
+nist_group!(p256, P256, nist256, "P256-SHA256");
+nist_group!(p384, P384, nist384, "P384-SHA384");
+#[cfg(feature = "p521")]
+nist_group!(p521, P521, nist521, "P521-SHA512");
+
+#[cfg(feature = "ristretto255")]
+mod ristretto255 {
+    use super::*;
+    use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+    use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+    use curve25519_dalek::scalar::Scalar;
+    use curve25519_dalek::traits::Identity;
+
+    const XMD: h2c::Xmd = h2c::Xmd::Sha512;
+
+    fn uniform_bytes(input: &[u8], dst: &[u8]) -> Result<[u8; 64]> {
+        let mut bytes = [0; 64];
+        bytes.copy_from_slice(&h2c::expand_message_xmd_with(XMD, input, dst, 64)?);
+        Ok(bytes)
+    }
+
+    pub struct Ristretto255;
+
+    impl Group for Ristretto255 {
+        type Scalar = Scalar;
+        type Element = RistrettoPoint;
+
+        const SUITE_ID: &'static str = "ristretto255-SHA512";
+        const NE: usize = 32;
+        const NS: usize = 32;
+
+        fn generator() -> RistrettoPoint {
+            RISTRETTO_BASEPOINT_POINT
+        }
+
+        fn identity() -> RistrettoPoint {
+            RistrettoPoint::identity()
+        }
+
+        fn add(a: &RistrettoPoint, b: &RistrettoPoint) -> RistrettoPoint {
+            a + b
+        }
+
+        fn mul(p: &RistrettoPoint, s: &Scalar) -> RistrettoPoint {
+            p * s
+        }
+
+        fn element_eq(a: &RistrettoPoint, b: &RistrettoPoint) -> bool {
+            a == b
+        }
+
+        fn scalar_mul(a: &Scalar, b: &Scalar) -> Scalar {
+            a * b
+        }
+
+        fn scalar_sub(a: &Scalar, b: &Scalar) -> Scalar {
+            a - b
+        }
+
+        fn scalar_invert(s: &Scalar) -> Scalar {
+            s.invert()
+        }
+
+        fn scalar_is_zero(s: &Scalar) -> bool {
+            *s == Scalar::zero()
+        }
+
+        fn scalar_eq(a: &Scalar, b: &Scalar) -> bool {
+            a == b
+        }
+
+        fn random_scalar<R: Rng>(rng: &mut R) -> Scalar {
+            loop {
+                let mut bytes = [0; 64];
+                rng.fill(&mut bytes[..]);
+                let s = Scalar::from_bytes_mod_order_wide(&bytes);
+                if s != Scalar::zero() {
+                    return s;
+                }
+            }
+        }
+
+        fn hash_to_group(input: &[u8], dst: &[u8]) -> Result<RistrettoPoint> {
+            Ok(RistrettoPoint::from_uniform_bytes(&uniform_bytes(input, dst)?))
+        }
+
+        fn hash_to_scalar(input: &[u8], dst: &[u8]) -> Result<Scalar> {
+            Ok(Scalar::from_bytes_mod_order_wide(&uniform_bytes(input, dst)?))
+        }
+
+        fn hash(input: &[u8]) -> Vec<u8> {
+            XMD.hash(&[input])
+        }
+
+        fn serialize_element(p: &RistrettoPoint) -> Vec<u8> {
+            p.compress().to_bytes().to_vec()
+        }
+
+        fn deserialize_element(bytes: &[u8]) -> Result<RistrettoPoint> {
+            if bytes.len() != Self::NE {
+                return Err(Error::InvalidPoint);
+            }
+            let p = CompressedRistretto::from_slice(bytes).decompress().ok_or(Error::InvalidPoint)?;
+            if p == RistrettoPoint::identity() {
+                return Err(Error::InvalidPoint);
+            }
+            Ok(p)
+        }
+
+        fn serialize_scalar(s: &Scalar) -> Vec<u8> {
+            s.to_bytes().to_vec()
+        }
+
+        fn deserialize_scalar(bytes: &[u8]) -> Result<Scalar> {
+            if bytes.len() != Self::NS {
+                return Err(Error::InvalidScalar);
+            }
+            let mut s = [0; 32];
+            s.copy_from_slice(bytes);
+            Scalar::from_canonical_bytes(s).ok_or(Error::InvalidScalar)
+        }
+    }
+}
+
+#[cfg(feature = "ristretto255")]
+pub use self::ristretto255::Ristretto255;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{SeedableRng, rngs::SmallRng};
+
+    fn check_group<G: Group>() {
+        let mut rng = SmallRng::from_seed([1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16]);
+        let s = G::random_scalar(&mut rng);
+        let p = G::mul(&G::generator(), &s);
+
+        let bytes = G::serialize_element(&p);
+        assert!(bytes.len() == G::NE);
+        assert!(G::element_eq(&G::deserialize_element(&bytes).unwrap(), &p));
+        assert!(G::deserialize_element(&bytes[1..]).is_err());
+        assert!(G::deserialize_element(&vec![0; G::NE]).is_err());
+
+        let bytes = G::serialize_scalar(&s);
+        assert!(bytes.len() == G::NS);
+        assert!(G::scalar_eq(&G::deserialize_scalar(&bytes).unwrap(), &s));
+        assert!(G::deserialize_scalar(&vec![0xff; G::NS]).is_err());
+
+        // s^-1 * (s * G) == G, and s*G - s*G is the identity
+        let q = G::mul(&p, &G::scalar_invert(&s));
+        assert!(G::element_eq(&q, &G::generator()));
+        let zero = G::scalar_sub(&s, &s);
+        assert!(G::scalar_is_zero(&zero));
+        assert!(G::element_eq(&G::mul(&p, &zero), &G::identity()));
+        assert!(G::element_eq(&G::add(&p, &G::identity()), &p));
+
+        let h = G::hash_to_group(b"input", b"dst").unwrap();
+        assert!(!G::element_eq(&h, &G::hash_to_group(b"input", b"other dst").unwrap()));
+    }
+
+    #[test]
+    fn test_p256() {
+        check_group::<P256>();
+    }
+
+    #[test]
+    fn test_p384() {
+        check_group::<P384>();
+    }
+
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_p521() {
+        check_group::<P521>();
+    }
+
+    #[cfg(feature = "ristretto255")]
+    #[test]
+    fn test_ristretto255() {
+        check_group::<Ristretto255>();
+    }
+}
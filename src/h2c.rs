@@ -0,0 +1,335 @@
+#![allow(non_snake_case)]
+
+// hash-to-curve as specified in RFC 9380, for the P256_XMD:SHA-256_SSWU_RO_ and
+// P384_XMD:SHA-384_SSWU_RO_ suites and, behind the p521 feature, the P-521 one
+
+use amcl::hash256::HASH256;
+use amcl::hash384::HASH384;
+use amcl::hash512::HASH512;
+use super::error::{Error, Result};
+
+// the hash functions of the RFC 9380 suites used here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Xmd {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Xmd {
+    // output size
+    pub fn b_in_bytes(&self) -> usize {
+        match self {
+            Xmd::Sha256 => 32,
+            Xmd::Sha384 => 48,
+            Xmd::Sha512 => 64,
+        }
+    }
+
+    // block size
+    pub fn r_in_bytes(&self) -> usize {
+        match self {
+            Xmd::Sha256 => 64,
+            Xmd::Sha384 | Xmd::Sha512 => 128,
+        }
+    }
+
+    pub fn hash(&self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Xmd::Sha256 => {
+                let mut sh = HASH256::new();
+                for part in parts.iter() {
+                    sh.process_array(part);
+                }
+                sh.hash().to_vec()
+            },
+            Xmd::Sha384 => {
+                let mut sh = HASH384::new();
+                for part in parts.iter() {
+                    sh.process_array(part);
+                }
+                sh.hash().to_vec()
+            },
+            Xmd::Sha512 => {
+                let mut sh = HASH512::new();
+                for part in parts.iter() {
+                    sh.process_array(part);
+                }
+                sh.hash().to_vec()
+            },
+        }
+    }
+}
+
+pub fn expand_message_xmd(msg: &[u8], dst: &[u8], len_in_bytes: usize) -> Result<Vec<u8>> {
+    expand_message_xmd_with(Xmd::Sha256, msg, dst, len_in_bytes)
+}
+
+pub fn expand_message_xmd_with(xmd: Xmd, msg: &[u8], dst: &[u8], len_in_bytes: usize) -> Result<Vec<u8>> {
+    let b_in_bytes = xmd.b_in_bytes();
+    let ell = (len_in_bytes + b_in_bytes - 1) / b_in_bytes;
+    if ell > 255 || len_in_bytes > 65535 {
+        return Err(Error::InvalidInput(format!("can't expand to {} bytes", len_in_bytes)));
+    }
+
+    // DSTs longer than 255 bytes are hashed down first
+    let dst = if dst.len() > 255 {
+        xmd.hash(&[&b"H2C-OVERSIZE-DST-"[..], dst])
+    } else {
+        dst.to_vec()
+    };
+    let mut dst_prime = dst.clone();
+    dst_prime.push(dst.len() as u8);
+
+    let z_pad = vec![0; xmd.r_in_bytes()];
+    let l_i_b_str = [(len_in_bytes >> 8) as u8, len_in_bytes as u8, 0];
+    let b_0 = xmd.hash(&[&z_pad[..], msg, &l_i_b_str[..], &dst_prime[..]]);
+
+    let mut uniform_bytes = Vec::with_capacity(ell*b_in_bytes);
+    let mut b_i = vec![0; b_in_bytes];
+    for i in 1..(ell+1) {
+        b_i = if i == 1 {
+            xmd.hash(&[&b_0[..], &[i as u8][..], &dst_prime[..]])
+        } else {
+            let xored : Vec<u8> = b_0.iter().zip(b_i.iter()).map(|(x, y)| x ^ y).collect();
+            xmd.hash(&[&xored[..], &[i as u8][..], &dst_prime[..]])
+        };
+        uniform_bytes.extend_from_slice(&b_i);
+    }
+
+    uniform_bytes.truncate(len_in_bytes);
+    Ok(uniform_bytes)
+}
+
+// hash_to_field and the simplified SWU map for a short Weierstrass curve with A = -3, as
+// all the NIST curves have. $z is the absolute value of the suite's Z, and $l the number of
+// bytes hashed per field element, ceil((ceil(log2(p)) + k) / 8)
+macro_rules! sswu_suite {
+    ($name:ident, $curve:ident, $xmd:expr, $z:expr, $l:expr) => {
+        pub mod $name {
+            use amcl::$curve::{big::BIG, dbig::DBIG, ecp::ECP, fp::FP, rom};
+            use crate::error::{Error, Result};
+            use super::{Xmd, expand_message_xmd_with};
+
+            pub const XMD: Xmd = $xmd;
+            pub const L: usize = $l;
+
+            pub fn field_modulus() -> BIG {
+                BIG::new_ints(&rom::MODULUS)
+            }
+
+            pub fn curve_order() -> BIG {
+                BIG::new_ints(&rom::CURVE_ORDER)
+            }
+
+            dbig_from_bytes!();
+
+            // hashes msg to count elements of the integers modulo m, either the field or the curve order
+            pub fn hash_to_field(msg: &[u8], dst: &[u8], count: usize, m: &BIG) -> Result<Vec<BIG>> {
+                let uniform_bytes = expand_message_xmd_with(XMD, msg, dst, count*L)?;
+                let elements = uniform_bytes.chunks(L)
+                    .map(|tv| dbig_from_bytes(tv).dmod(m))
+                    .collect();
+
+                Ok(elements)
+            }
+
+            // the integer in [0, p) of a field element, which may be only partly reduced
+            fn fp_value(a: &FP) -> BIG {
+                let mut a = FP::new_copy(a);
+                crate::ct::$name::reduce_once(&a.redc(), &field_modulus())
+            }
+
+            fn fp_equals(a: &FP, b: &FP) -> bool {
+                crate::ct::$name::big_eq(&fp_value(a), &fp_value(b))
+            }
+
+            fn sgn0(a: &FP) -> isize {
+                fp_value(a).parity()
+            }
+
+            // x^3 + A*x + B
+            fn curve_rhs(x: &FP, A: &FP, B: &FP) -> FP {
+                let mut gx = FP::new_copy(x);
+                gx.sqr();
+                gx.add(A);
+                gx.mul(x);
+                gx.add(B);
+                gx.reduce();
+                gx
+            }
+
+            // both candidates are always computed and selected with cmove, so the running time
+            // doesn't depend on u
+            pub fn map_to_curve_sswu(u: &BIG) -> ECP {
+                let mut A = FP::new_int(3);
+                A.neg();
+                let B = FP::new_big(&BIG::new_ints(&rom::CURVE_B));
+                let mut Z = FP::new_int($z);
+                Z.neg();
+                let u = FP::new_big(u);
+
+                // tv1 = Z * u^2, tv2 = tv1^2 + tv1
+                let mut tv1 = FP::new_copy(&u);
+                tv1.sqr();
+                tv1.mul(&Z);
+                let mut tv2 = FP::new_copy(&tv1);
+                tv2.sqr();
+                tv2.add(&tv1);
+                tv2.reduce();
+
+                // x1 = (-B / A) * (1 + 1 / tv2), or B / (Z * A) when tv2 is 0
+                let mut inv_A = FP::new_copy(&A);
+                inv_A.inverse();
+                let mut x1 = FP::new_copy(&tv2);
+                x1.inverse();
+                x1.add(&FP::new_int(1));
+                x1.mul(&B);
+                x1.mul(&inv_A);
+                x1.neg();
+                x1.reduce();
+                let mut exceptional_x1 = FP::new_copy(&Z);
+                exceptional_x1.mul(&A);
+                exceptional_x1.inverse();
+                exceptional_x1.mul(&B);
+                exceptional_x1.reduce();
+                x1.cmove(&exceptional_x1, crate::ct::$name::big_is_zero(&fp_value(&tv2)).unwrap_u8() as isize);
+
+                // x2 = tv1 * x1
+                let mut x2 = FP::new_copy(&tv1);
+                x2.mul(&x1);
+                x2.reduce();
+
+                let mut gx1 = curve_rhs(&x1, &A, &B);
+                let mut gx2 = curve_rhs(&x2, &A, &B);
+                let y1 = gx1.sqrt();
+                let y2 = gx2.sqrt();
+
+                // gx1 is a square iff its candidate root squares back to it
+                let mut y1_squared = FP::new_copy(&y1);
+                y1_squared.sqr();
+                let e1 = fp_equals(&y1_squared, &gx1) as isize;
+
+                let mut x = x2;
+                x.cmove(&x1, e1);
+                let mut y = y2;
+                y.cmove(&y1, e1);
+
+                let mut neg_y = FP::new_copy(&y);
+                neg_y.neg();
+                neg_y.reduce();
+                y.cmove(&neg_y, (sgn0(&u) != sgn0(&y)) as isize);
+
+                ECP::new_bigs(&x.redc(), &y.redc())
+            }
+
+            // the cofactor of the NIST curves is 1, so no clearing is needed
+            pub fn hash_to_curve(msg: &[u8], dst: &[u8]) -> Result<ECP> {
+                let u = hash_to_field(msg, dst, 2, &field_modulus())?;
+                let mut Q = map_to_curve_sswu(&u[0]);
+                Q.add(&map_to_curve_sswu(&u[1]));
+                if Q.is_infinity() {
+                    return Err(Error::HashToCurve);
+                }
+
+                Ok(Q)
+            }
+        }
+    };
+}
+
+// P256_XMD:SHA-256_SSWU_RO_, the curve of the legacy protocol
+sswu_suite!(p256, nist256, Xmd::Sha256, 10, 48);
+// P384_XMD:SHA-384_SSWU_RO_, the curve of RFC 9578 type 0x0001 tokens
+sswu_suite!(p384, nist384, Xmd::Sha384, 12, 72);
+// P521_XMD:SHA-512_SSWU_RO_
+#[cfg(feature = "p521")]
+sswu_suite!(p521, nist521, Xmd::Sha512, 4, 98);
+
+pub use self::p256::{field_modulus, curve_order, hash_to_field, map_to_curve_sswu, hash_to_curve};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::curve::ecp::ECP;
+    use crate::converters;
+
+    // RFC 9380, section K.1
+    #[test]
+    fn test_expand_message_xmd() {
+        let dst = b"QUUX-V01-CS02-with-expander-SHA256-128";
+
+        let uniform_bytes = expand_message_xmd(b"", dst, 0x20).unwrap();
+        assert!(hex::encode(&uniform_bytes) == "68a985b87eb6b46952128911f2a4412bbc302a9d759667f87f7a21d803f07235");
+
+        let uniform_bytes = expand_message_xmd(b"abc", dst, 0x20).unwrap();
+        assert!(hex::encode(&uniform_bytes) == "d8ccab23b5985ccea865c6c97b6e5b8350e794e603b4b97902f53a8a0d605615");
+
+        let uniform_bytes = expand_message_xmd(b"", dst, 0x80).unwrap();
+        assert!(hex::encode(&uniform_bytes) == concat!(
+            "af84c27ccfd45d41914fdff5df25293e221afc53d8ad2ac06d5e3e29485dadbe",
+            "e0d121587713a3e0dd4d5e69e93eb7cd4f5df4cd103e188cf60cb02edc3edf18",
+            "eda8576c412b18ffb658e3dd6ec849469b979d444cf7b26911a08e63cf31f9dc",
+            "c541708d3491184472c2c29bb749d4286b004ceb5ee6b9a7fa5b646c993f0ced"));
+
+        assert!(expand_message_xmd(b"abc", dst, 256*32).is_err());
+    }
+
+    // RFC 9380, section J.1.1
+    #[test]
+    fn test_hash_to_curve() {
+        let dst = b"QUUX-V01-CS02-with-P256_XMD:SHA-256_SSWU_RO_";
+        let vectors = [
+            (&b""[..],
+             "2c15230b26dbc6fc9a37051158c95b79656e17a1a920b11394ca91c44247d3e4",
+             "8a7a74985cc5c776cdfe4b1f19884970453912e9d31528c060be9ab5c43e8415"),
+            (&b"abc"[..],
+             "0bb8b87485551aa43ed54f009230450b492fead5f1cc91658775dac4a3388a0f",
+             "5c41b3d0731a27a7b14bc0bf0ccded2d8751f83493404c84a88e71ffd424212e"),
+        ];
+
+        for (msg, x, y) in vectors.iter() {
+            let mut P = hash_to_curve(msg, dst).unwrap();
+            P.affine();
+            let expected = ECP::new_bigs(
+                &converters::big_from_bytes(&hex::decode(x).unwrap()),
+                &converters::big_from_bytes(&hex::decode(y).unwrap()));
+            assert!(P == expected);
+        }
+    }
+
+    // RFC 9380, section J.2.1
+    #[test]
+    fn test_hash_to_curve_p384() {
+        let dst = b"QUUX-V01-CS02-with-P384_XMD:SHA-384_SSWU_RO_";
+        let mut P = p384::hash_to_curve(b"", dst).unwrap();
+        let mut bytes = [0; 97];
+        P.affine();
+        P.tobytes(&mut bytes, false);
+        assert!(hex::encode(&bytes[1..49]) == concat!(
+            "eb9fe1b4f4e14e7140803c1d99d0a93cd823d2b024040f9c",
+            "067a8eca1f5a2eeac9ad604973527a356f3fa3aeff0e4d83"));
+        assert!(hex::encode(&bytes[49..]) == concat!(
+            "0c21708cff382b7f4643c07b105c2eaec2cead93a917d825",
+            "601e63c8f21f6abd9abc22c93c2bed6f235954b25048bb1a"));
+    }
+
+    // RFC 9380, section J.3.1
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_hash_to_curve_p521() {
+        let dst = b"QUUX-V01-CS02-with-P521_XMD:SHA-512_SSWU_RO_";
+        let mut P = p521::hash_to_curve(b"", dst).unwrap();
+        let mut bytes = [0; 133];
+        P.affine();
+        P.tobytes(&mut bytes, false);
+        assert!(hex::encode(&bytes[1..67]) == concat!(
+            "00fd767cebb2452030358d0e9cf907f525f50920c8f607889a6a35680727f64f",
+            "4d66b161fafeb2654bea0d35086bec0a10b30b14adef3556ed9f7f1bc23cecc9",
+            "c088"));
+        assert!(hex::encode(&bytes[67..]) == concat!(
+            "0169ba78d8d851e930680322596e39c78f4fe31b97e57629ef6460ddd68f8763",
+            "fd7bd767a4e94a80d3d21a3c2ee98347e024fc73ee1c27166dc3fe5eeef782be",
+            "411d"));
+    }
+}
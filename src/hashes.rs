@@ -1,12 +1,59 @@
 use amcl::hash256::HASH256;
 use super::types::curve::{big, ecp::ECP};
+use super::h2c;
+use super::secret::Zeroizing;
+use super::error::{Error, Result};
+
+// domain separation tag of tokens hashed with the swu method
+pub const SWU_DST: &[u8] = b"privacypass-rs-V01-CS02-with-P256_XMD:SHA-256_SSWU_RO_";
+
+// how tokens are hashed to the curve under a key, named as in the Cloudflare commitment format
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum H2cMethod {
+    // try-and-increment, as in hash_to_curve. kept for keys that issued tokens before swu
+    Increment,
+    // RFC 9380 P256_XMD:SHA-256_SSWU_RO_ under SWU_DST
+    Swu,
+}
 
-use std::error::Error;
+impl Default for H2cMethod {
+    fn default() -> Self {
+        H2cMethod::Increment
+    }
+}
 
+impl H2cMethod {
+    pub fn is_increment(&self) -> bool {
+        *self == H2cMethod::Increment
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            H2cMethod::Increment => "increment",
+            H2cMethod::Swu => "swu",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "increment" => Some(H2cMethod::Increment),
+            "swu" => Some(H2cMethod::Swu),
+            _ => None,
+        }
+    }
+}
+
+pub fn hash_to_curve_with(method: H2cMethod, data: &[u8]) -> Result<ECP> {
+    match method {
+        H2cMethod::Increment => hash_to_curve(data),
+        H2cMethod::Swu => h2c::hash_to_curve(data, SWU_DST),
+    }
+}
 
 // H_1
 // more-or-less based on ECVRF_hash_to_curve1
-pub fn hash_to_curve(data: &[u8]) -> Result<ECP, Box<Error>> {
+pub fn hash_to_curve(data: &[u8]) -> Result<ECP> {
     let mut ctr : [u8; 4] = [0; 4];
     let mut sh = HASH256::new();
     sh.process_array(&"1.2.840.10045.3.1.7 point generation seed".as_bytes());
@@ -40,7 +87,7 @@ pub fn hash_to_curve(data: &[u8]) -> Result<ECP, Box<Error>> {
         data_to_hash = h.to_vec();
     }
 
-    return Err("infinity".into());
+    return Err(Error::HashToCurve);
 }
 
 // H_3
@@ -56,16 +103,36 @@ pub fn hash_points(points: &[&ECP]) -> Vec<u8> {
     sh.hash().to_vec()
 }
 
-// H_2
-pub fn hash_for_redemption(t: &[u8], n: &ECP) -> Vec<u8> {
-    let mut input = vec![];
-    input.extend(t.to_vec().iter().cloned());
+// H_2. the input and the derived key are secrets, and are wiped once dropped
+pub fn hash_for_redemption(t: &[u8], n: &ECP) -> Zeroizing<Vec<u8>> {
     let bytes_len = big::MODBYTES + big::MODBYTES + 1;
-    let mut bytes = vec![0; bytes_len];
+    let mut bytes = Zeroizing::new(vec![0; bytes_len]);
     n.tobytes(&mut bytes, false);
-    input.append(&mut bytes);
+    // sized up front, so no copy is left behind by a reallocation
+    let mut input = Zeroizing::new(Vec::with_capacity(t.len() + bytes_len));
+    input.extend_from_slice(t);
+    input.extend_from_slice(&bytes);
+
+    Zeroizing::new(hmac(b"hash_derive_key", &input))
+}
+
+// digest under which a spent token is recorded
+pub fn hash_token(t: &[u8]) -> Vec<u8> {
+    let mut sh = HASH256::new();
+    sh.process_array(t);
+    sh.hash().to_vec()
+}
 
-    hmac(b"hash_derive_key", &input)
+// short identifier of an issuer key, derived from its public point Y
+#[allow(non_snake_case)]
+pub fn hash_key_id(Y: &ECP) -> String {
+    let bytes_len = big::MODBYTES + 1;
+    let mut bytes = vec![0; bytes_len];
+    Y.tobytes(&mut bytes, true);
+
+    let mut sh = HASH256::new();
+    sh.process_array(&bytes);
+    hex::encode(&sh.hash()[..8])
 }
 
 pub fn hash_for_request_binding(derived_key: &[u8], shared_info: &[u8]) -> Vec<u8> {
@@ -75,10 +142,10 @@ pub fn hash_for_request_binding(derived_key: &[u8], shared_info: &[u8]) -> Vec<u
     hmac(derived_key, &input)
 }
 
+// the key and the pads derived from it are never logged, and are wiped once dropped
 pub fn hmac(key: &[u8], input: &[u8]) -> Vec<u8> {
-    debug!("key: {:x?}, input: {:x?}", key, input);
     let block_size = 64;
-    let mut processed_key = vec![0; block_size];
+    let mut processed_key = Zeroizing::new(vec![0; block_size]);
     if key.len() > block_size {
         let mut sh = HASH256::new();
         sh.process_array(key);
@@ -88,21 +155,18 @@ pub fn hmac(key: &[u8], input: &[u8]) -> Vec<u8> {
         processed_key[..key.len()].copy_from_slice(&key);
     }
     let key = &processed_key;
-    debug!("processed key: {:x?}", key);
 
-    let mut o_key_pad = vec![0; key.len()];
+    let mut o_key_pad = Zeroizing::new(vec![0; key.len()]);
     o_key_pad.copy_from_slice(key);
     for k in o_key_pad.iter_mut() {
         *k = *k^0x5c;
     }
-    debug!("o_key_pad: {:x?}", o_key_pad);
 
-    let mut i_key_pad = vec![0; key.len()];
+    let mut i_key_pad = Zeroizing::new(vec![0; key.len()]);
     i_key_pad.copy_from_slice(key);
     for k in i_key_pad.iter_mut() {
         *k = *k^0x36;
     }
-    debug!("i_key_pad: {:x?}", i_key_pad);
 
     let mut inner = HASH256::new();
     inner.process_array(&i_key_pad);
@@ -116,7 +180,10 @@ pub fn hmac(key: &[u8], input: &[u8]) -> Vec<u8> {
     outer.hash().to_vec()
 }
 
+#[cfg(test)]
 mod test {
+    use super::*;
+    use crate::types::curve::big::BIG;
 
     #[test]
     fn test_hash_to_curve() {
@@ -137,7 +204,7 @@ mod test {
 
     #[test]
     fn test_hmac() {
-        env_logger::try_init();
+        let _ = env_logger::try_init();
         let h = hmac(b"key", b"The quick brown fox jumps over the lazy dog");
         debug!("hmac: {:x?}", h);
         assert!(h == &[0xf7, 0xbc, 0x83, 0xf4, 0x30, 0x53, 0x84, 0x24, 0xb1, 0x32, 0x98, 0xe6, 0xaa, 0x6f, 0xb1, 0x43, 0xef, 0x4d, 0x59, 0xa1, 0x49, 0x46, 0x17, 0x59, 0x97, 0x47, 0x9d, 0xbc, 0x2d,
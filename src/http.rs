@@ -0,0 +1,62 @@
+use super::types;
+use super::error::{Error, Result};
+
+// headers used by the Privacy Pass browser extension
+pub const TOKEN_HEADER: &str = "challenge-bypass-token";
+// the RedeemStatus of a redemption, next to the status code the extension looks at
+pub const STATUS_HEADER: &str = "challenge-bypass-status";
+
+// the token header carries the same base64 JSON request as bl_sig_req, while host and path
+// come from the HTTP request itself
+pub fn wrap_header_redemption(header_value: &str, host: &str, path: &str) -> types::ClientRequestWrapper {
+    types::ClientRequestWrapper {
+        bl_sig_req: header_value.trim().to_string(),
+        host: host.to_string(),
+        http: path.to_string(),
+    }
+}
+
+pub fn send_redemption(url: &str, request: &types::ClientRequestWrapper) -> Result<types::RedeemResponse> {
+    let client = reqwest::Client::new();
+    let mut response = client.get(url)
+        .header(TOKEN_HEADER, request.bl_sig_req.as_str())
+        .send()?;
+    debug!("redemption response status: {}", response.status());
+
+    let status = response.headers().get(STATUS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(types::RedeemStatus::from_name);
+    let mut buf = vec![];
+    response.copy_to(&mut buf)?;
+    let message = String::from_utf8_lossy(&buf).to_string();
+
+    match status {
+        Some(types::RedeemStatus::Success) => Ok(types::RedeemResponse::success()),
+        Some(status) => Ok(types::RedeemResponse {
+            status: status,
+            message: message,
+        }),
+        // servers without the status header only answer successful redemptions with "success"
+        None if response.status().is_success() && message == "success" => Ok(types::RedeemResponse::success()),
+        None => Err(Error::RedemptionFailed(message)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrap_header_redemption() {
+        let req = types::ClientRequest {
+            type_f: "Redeem".to_string(),
+            contents: vec!["dG9rZW4=".to_string(), "YmluZGluZw==".to_string()],
+        };
+        let header_value = base64::encode(&serde_json::to_string(&req).unwrap());
+
+        let wrapped = wrap_header_redemption(&format!("{}\r\n", header_value), "example.com", "/index.html");
+        assert!(wrapped.bl_sig_req == header_value);
+        assert!(wrapped.host == "example.com");
+        assert!(wrapped.http == "/index.html");
+    }
+}
@@ -7,14 +7,27 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod error;
+#[macro_use]
 pub mod converters;
 pub mod hashes;
+pub mod h2c;
+pub mod group;
 pub mod random;
+pub mod ct;
+pub mod secret;
 pub mod ecc;
 pub mod types;
 pub mod net;
+pub mod http;
 pub mod db;
 pub mod mac;
+pub mod commitment;
+pub mod voprf;
+pub mod blind_rsa;
+pub mod token;
 
 pub mod client;
 pub mod server;
+
+pub use error::Error;
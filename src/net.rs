@@ -1,21 +1,149 @@
 use std::net::{Shutdown, TcpStream};
-use std::error::Error;
 use serde::Serialize;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
-pub fn send_request<T: Serialize>(address: &str, request: &T) -> Result<Vec<u8>, Box<Error>> {
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::error::{Error, Result};
+
+pub const MAX_MESSAGE_SIZE: usize = 10*1024*1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+  // every message is preceded by its length as a big-endian u32
+  LengthPrefixed,
+  // requests are bare JSON values and responses run until the connection closes,
+  // as done by the original challenge-bypass-server
+  Legacy,
+}
+
+impl Framing {
+  pub fn from_prepend_size(should_prepend_size: bool) -> Framing {
+    if should_prepend_size {
+      Framing::LengthPrefixed
+    } else {
+      Framing::Legacy
+    }
+  }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, msg: &[u8]) -> Result<()> {
+  if msg.len() > MAX_MESSAGE_SIZE {
+    return Err(Error::MessageTooLarge(msg.len()));
+  }
+  writer.write_u32::<BigEndian>(msg.len() as u32)?;
+  writer.write_all(msg)?;
+  writer.flush()?;
+  Ok(())
+}
+
+// returns None if the stream was closed before a new frame started
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+  let len = match reader.read_u32::<BigEndian>() {
+    Ok(len) => len as usize,
+    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  if len > MAX_MESSAGE_SIZE {
+    return Err(Error::MessageTooLarge(len));
+  }
+
+  let mut buf = vec![0; len];
+  reader.read_exact(&mut buf)?;
+  Ok(Some(buf))
+}
+
+// reads a single JSON value, however many reads it takes to arrive
+pub fn read_legacy_request<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+  let limited = reader.by_ref().take(MAX_MESSAGE_SIZE as u64);
+  let mut values = serde_json::Deserializer::from_reader(limited).into_iter::<serde_json::Value>();
+  match values.next() {
+    Some(value) => Ok(Some(serde_json::to_vec(&value?)?)),
+    None => Ok(None),
+  }
+}
+
+pub fn read_legacy_response<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+  let mut buf = vec![];
+  reader.by_ref().take(MAX_MESSAGE_SIZE as u64 + 1).read_to_end(&mut buf)?;
+  if buf.len() > MAX_MESSAGE_SIZE {
+    return Err(Error::MessageTooLarge(buf.len()));
+  }
+  Ok(buf)
+}
+
+pub fn send_request<T: Serialize>(address: &str, request: &T, framing: Framing) -> Result<Vec<u8>> {
   let mut stream = TcpStream::connect(address)?;
   println!("Connected to the server!");
 
   let request_str = serde_json::to_string(&request)?;
   let msg = &request_str.into_bytes();
-  stream.write(&msg)?;
-  stream.flush()?;
-
-  let mut buf = vec![0; 10*1024];
-  let n = stream.read(&mut buf)?;
+  let buf = match framing {
+    Framing::LengthPrefixed => {
+      write_frame(&mut stream, &msg)?;
+      read_frame(&mut stream)?.ok_or_else(|| Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)))?
+    },
+    Framing::Legacy => {
+      stream.write_all(&msg)?;
+      stream.flush()?;
+      read_legacy_response(&mut stream)?
+    },
+  };
   stream.shutdown(Shutdown::Both)?;
-  let buf = &buf[..n];
   println!("buf: {:?}", buf);
-  Ok(buf.to_vec())
+  Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::io::Cursor;
+
+  // hands out at most one byte per read, like a badly segmented stream
+  struct Trickle<R: Read>(R);
+
+  impl<R: Read> Read for Trickle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let len = if buf.is_empty() { 0 } else { 1 };
+      self.0.read(&mut buf[..len])
+    }
+  }
+
+  #[test]
+  fn test_frame_roundtrip() {
+    let mut buf = vec![];
+    write_frame(&mut buf, b"first").unwrap();
+    write_frame(&mut buf, &vec![7; 100*1024]).unwrap();
+
+    let mut reader = Trickle(Cursor::new(buf));
+    assert!(read_frame(&mut reader).unwrap().unwrap() == b"first");
+    assert!(read_frame(&mut reader).unwrap().unwrap() == vec![7; 100*1024]);
+    assert!(read_frame(&mut reader).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_frame_too_large() {
+    let mut buf = vec![];
+    buf.write_u32::<BigEndian>(MAX_MESSAGE_SIZE as u32 + 1).unwrap();
+    assert!(matches!(read_frame(&mut Cursor::new(buf)), Err(Error::MessageTooLarge(_))));
+  }
+
+  #[test]
+  fn test_frame_truncated() {
+    let mut buf = vec![];
+    write_frame(&mut buf, b"truncated").unwrap();
+    buf.truncate(6);
+    assert!(read_frame(&mut Cursor::new(buf)).is_err());
+  }
+
+  #[test]
+  fn test_legacy_request() {
+    let msg = br#"{"bl_sig_req":"abc","host":"","http":""}"#;
+    let mut reader = Trickle(Cursor::new(msg.to_vec()));
+    let read = read_legacy_request(&mut reader).unwrap().unwrap();
+    let expected : serde_json::Value = serde_json::from_slice(msg).unwrap();
+    let read : serde_json::Value = serde_json::from_slice(&read).unwrap();
+    assert!(read == expected);
+    assert!(read_legacy_request(&mut reader).unwrap().is_none());
+  }
 }
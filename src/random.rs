@@ -1,12 +1,10 @@
-use super::{hashes, types, converters};
+use super::{hashes, types, converters, ct};
 
 use super::types::curve::big::BIG;
 use amcl::rand::RAND;
 
 use rand::Rng;
 
-use std::cmp::Ordering;
-
 use sha3::Shake256;
 use sha3::digest::{Input, ExtendableOutput ,XofReader};
 
@@ -68,18 +66,15 @@ pub fn rand_scalar_from_rng<R: Rng>(rng: &mut R) -> types::curve::big::BIG {
 pub fn rand_scalar_from_prng(prng: &mut XofReader) -> types::curve::big::BIG {
     let curve_order_big = types::curve::big::BIG::new_ints(&types::curve::rom::CURVE_ORDER);
 
-    let zero = types::curve::big::BIG::new();
     let bytes_len = types::curve::big::MODBYTES;
     let mut bytes = vec![0; bytes_len];
     prng.read(&mut bytes);
     let mut c = converters::big_from_bytes(&bytes);
     c.norm();
-    if c.cmp(&curve_order_big) != Ordering::Less {
-        c.sub(&curve_order_big);
-    }
-    if c.cmp(&zero) != Ordering::Greater {
-        c.add(&curve_order_big);
-    }
+    // 2^256 < 2n, so a single conditional subtraction reduces c. a zero result is mapped to n
+    // as before, and neither step branches on the value
+    let mut c = ct::reduce_once(&c, &curve_order_big);
+    c.cmove(&curve_order_big, ct::big_is_zero(&c).unwrap_u8() as isize);
     c.norm();
 
     c
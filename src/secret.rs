@@ -0,0 +1,81 @@
+// wrappers for secret keys, blinding factors and derived keys, which are wiped from memory
+// when dropped and never printed. BIG is Copy, so temporaries made by the curve arithmetic
+// are not covered, only the long-lived values
+
+use super::types::curve::big::BIG;
+
+use std::fmt;
+use std::ops::Deref;
+
+use zeroize::Zeroize;
+pub use zeroize::Zeroizing;
+
+// scalars that can be held in a SecretScalar: those of the legacy curve and of RFC 9578
+// type 0x0001 tokens
+pub trait Wipe: Clone {
+    fn wipe(&mut self);
+}
+
+impl Wipe for BIG {
+    fn wipe(&mut self) {
+        self.w.zeroize();
+    }
+}
+
+impl Wipe for amcl::nist384::big::BIG {
+    fn wipe(&mut self) {
+        self.w.zeroize();
+    }
+}
+
+// derefs to the scalar, so it can be passed wherever a &BIG is expected
+#[derive(Clone)]
+pub struct SecretScalar<S: Wipe = BIG>(S);
+
+impl<S: Wipe> SecretScalar<S> {
+    pub fn new(s: S) -> Self {
+        SecretScalar(s)
+    }
+}
+
+impl<S: Wipe> Deref for SecretScalar<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S: Wipe> Zeroize for SecretScalar<S> {
+    fn zeroize(&mut self) {
+        self.0.wipe();
+    }
+}
+
+impl<S: Wipe> Drop for SecretScalar<S> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl<S: Wipe> fmt::Debug for SecretScalar<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretScalar(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::converters;
+
+    #[test]
+    fn test_secret_scalar() {
+        let mut s = SecretScalar::new(converters::big_from_int(42));
+        assert!(format!("{:?}", s) == "SecretScalar(..)");
+        assert!(!s.iszilch());
+
+        s.zeroize();
+        assert!(s.iszilch());
+    }
+}
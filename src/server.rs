@@ -1,13 +1,21 @@
 #![allow(non_snake_case)]
 
-use super::{hashes, random, converters, ecc, types, client, mac, db};
+use super::{hashes, random, converters, ecc, types, client, mac, db, net, http, token, voprf, blind_rsa, ct};
+use super::group::Group;
+use super::secret::{SecretScalar, Zeroizing};
+use super::token::VoprfGroup;
+use super::error::{Error, Result};
 
-use std::error::Error;
 use rand::Rng;
 use std::collections::HashMap;
-use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use config::{ConfigError, Config, File};
+use openssl::pkey::Private;
+use openssl::rsa::Rsa;
+use threadpool::ThreadPool;
 
 #[derive(Debug, Deserialize)]
 pub struct ServerSettings {
@@ -15,13 +23,86 @@ pub struct ServerSettings {
     pub secret_key_path: String,
     pub commitment_path: String,
     pub max_tokens: u8,
+    #[serde(default = "default_num_threads")]
+    pub num_threads: usize,
+    // threads signing the points of a single issue request
+    #[serde(default = "default_issue_workers")]
+    pub issue_workers: usize,
+    #[serde(default = "default_connection_timeout_secs")]
+    pub connection_timeout_secs: u64,
+    #[serde(default = "default_should_prepend_size")]
+    pub should_prepend_size: bool,
+    #[serde(default)]
+    pub http_listen_address: Option<String>,
+    #[serde(default)]
+    pub redeem_only_keys: Vec<KeySettings>,
+    #[serde(default)]
+    pub commitment_signing_key_path: Option<String>,
+    #[serde(default = "default_commitment_version")]
+    pub commitment_version: String,
+    #[serde(default = "default_commitment_validity_secs")]
+    pub commitment_validity_secs: u64,
+    // hash-to-curve method written into new commitments by keygen
+    #[serde(default)]
+    pub h2c_method: hashes::H2cMethod,
+    // enables RFC 9578 type 0x0001 issuance over HTTP
+    #[serde(default)]
+    pub token_secret_key_path: Option<String>,
+    // enables RFC 9578 type 0x0002 issuance over HTTP, with a 2048 bit RSA key
+    #[serde(default)]
+    pub public_token_secret_key_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeySettings {
+    pub secret_key_path: String,
+    pub commitment_path: String,
+}
+
+fn default_num_threads() -> usize {
+    8
+}
+
+fn default_issue_workers() -> usize {
+    1
+}
+
+fn default_connection_timeout_secs() -> u64 {
+    30
+}
+
+fn default_should_prepend_size() -> bool {
+    true
+}
+
+fn default_commitment_version() -> String {
+    "1.0".to_string()
+}
+
+fn default_commitment_validity_secs() -> u64 {
+    365*24*60*60
 }
 
 impl ServerSettings {
-    pub fn new(config_path: &str) -> Result<Self, ConfigError> {
+    pub fn new(config_path: &str) -> Result<Self> {
         let mut s = Config::new();
         s.merge(File::with_name(config_path))?;
-        s.try_into()
+        let settings : ServerSettings = s.try_into()?;
+        if settings.max_tokens == 0 {
+            return Err(ConfigError::Message("max_tokens must be at least 1".to_string()).into());
+        }
+        if settings.num_threads == 0 {
+            return Err(ConfigError::Message("num_threads must be at least 1".to_string()).into());
+        }
+        if settings.issue_workers == 0 {
+            return Err(ConfigError::Message("issue_workers must be at least 1".to_string()).into());
+        }
+
+        Ok(settings)
+    }
+
+    pub fn framing(&self) -> net::Framing {
+        net::Framing::from_prepend_size(self.should_prepend_size)
     }
 }
 
@@ -32,17 +113,94 @@ pub fn sign_blinded_token(
     blinded_token.mul(x)
 }
 
+// the status a failed redemption is answered with, None for requests that can't be parsed
+pub fn redeem_status(e: &Error) -> Option<types::RedeemStatus> {
+    match e {
+        Error::DoubleSpend => Some(types::RedeemStatus::DoubleSpend),
+        Error::InvalidMac => Some(types::RedeemStatus::BadBinding),
+        Error::UnknownKey(_) => Some(types::RedeemStatus::UnknownKey),
+        Error::Storage(_) | Error::Io(_) => Some(types::RedeemStatus::ServerError),
+        _ => None,
+    }
+}
+
+// below this many points per worker, handing them to the pool costs more than it saves
+const MIN_POINTS_PER_WORKER: usize = 8;
+
+// runs f over every chunk on the pool, and returns the results in the order of the chunks. a
+// worker that panics fails the request instead of the calling thread
+fn run_chunks<C, T, F>(pool: &ThreadPool, chunks: Vec<C>, f: F) -> Result<Vec<T>>
+    where C: Send + 'static, T: Send + 'static, F: Fn(C) -> T + Send + Sync + 'static {
+
+    let f = Arc::new(f);
+    let (tx, rx) = mpsc::channel();
+    let count = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let f = f.clone();
+        let tx = tx.clone();
+        pool.execute(move || {
+            let _ = tx.send((i, f(chunk)));
+        });
+    }
+    drop(tx);
+
+    // the sender of a panicked worker is dropped without sending
+    let mut results : Vec<(usize, T)> = rx.iter().collect();
+    if results.len() != count {
+        return Err(Error::IssueWorkerFailed);
+    }
+    results.sort_by_key(|(i, _)| *i);
+
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// decodes and signs the blinded tokens of an issue request, split across the workers of pool, or
+// on the calling thread without one. the points are returned in request order, so the proof
+// doesn't depend on the split
+pub fn sign_blinded_tokens(
+    x: &SecretScalar,
+    blinded_tokens: &[String],
+    pool: Option<&ThreadPool>) -> Result<(Vec<types::curve::ecp::ECP>, Vec<types::curve::ecp::ECP>)> {
+
+    fn sign_chunk(x: &SecretScalar, chunk: &[String]) -> Result<Vec<(types::curve::ecp::ECP, types::curve::ecp::ECP)>> {
+        chunk.iter()
+            .map(|m_str| {
+                let M = ecc::ecp_from_bytes(&base64::decode(m_str)?)?;
+                Ok((M, sign_blinded_token(x, &M)))
+            })
+            .collect()
+    }
+
+    let workers = pool.map(|pool| pool.max_count()).unwrap_or(1);
+    let chunk_size = std::cmp::max((blinded_tokens.len() + workers - 1) / workers, MIN_POINTS_PER_WORKER);
+    let signed = match pool {
+        Some(pool) if blinded_tokens.len() > chunk_size => {
+            let x = x.clone();
+            let chunks = blinded_tokens.chunks(chunk_size).map(|chunk| chunk.to_vec()).collect();
+            let mut signed = vec![];
+            for chunk_signed in run_chunks(pool, chunks, move |chunk| sign_chunk(&x, &chunk))? {
+                signed.extend(chunk_signed?);
+            }
+            signed
+        },
+        _ => sign_chunk(x, blinded_tokens)?,
+    };
+
+    Ok(signed.into_iter().unzip())
+}
+
+// key holds the tables of the issuer's G and Y
 pub fn batch_dleq<R: Rng>(
     x: &types::curve::big::BIG,
     Zs: &[types::curve::ecp::ECP], Ms: &[types::curve::ecp::ECP],
-    Y: &types::curve::ecp::ECP, G: &types::curve::ecp::ECP,
+    key: &client::DleqVerifier,
     rng: &mut R) -> (types::curve::big::BIG, types::curve::big::BIG) {
 
     let mut ps = vec![];
-    ps.push(G);
-    debug!("G: {}", G);
-    ps.push(Y);
-    debug!("Y: {}", Y);
+    ps.push(&key.G);
+    debug!("G: {}", key.G);
+    ps.push(&key.Y);
+    debug!("Y: {}", key.Y);
     for i in 0..Ms.len() {
         ps.push(&Ms[i]);
         debug!("Ms[{}]: {}", i, Ms[i]);
@@ -52,33 +210,33 @@ pub fn batch_dleq<R: Rng>(
 
     let mut prng = random::init_prng(&ps);
 
-    let mut M = types::curve::ecp::ECP::new();
-    let mut Z = types::curve::ecp::ECP::new();
+    let mut cs = vec![];
     for i in 0..Zs.len() {
         let c = random::rand_scalar_from_prng(&mut prng);
         debug!("c[{}]: {}", i, c);
-        M.add(&Ms[i].mul(&c));
-        Z.add(&Zs[i].mul(&c));
+        cs.push(c);
     }
+    let M = ecc::multi_mul(&Ms[..Zs.len()], &cs);
+    let Z = ecc::multi_mul(Zs, &cs);
 
-    dleq(x, &Z, &M, Y, G, rng)
+    dleq(x, &Z, &M, key, rng)
 }
 
 
 pub fn dleq<R: Rng>(
     x: &types::curve::big::BIG,
     Z: &types::curve::ecp::ECP, M: &types::curve::ecp::ECP,
-    Y: &types::curve::ecp::ECP, G: &types::curve::ecp::ECP,
+    key: &client::DleqVerifier,
     rng: &mut R) -> (types::curve::big::BIG, types::curve::big::BIG) {
 
-    let zero = types::curve::big::BIG::new();
+    let (G, Y) = (&key.G, &key.Y);
 
     let curve_order_big = types::curve::big::BIG::new_ints(&types::curve::rom::CURVE_ORDER);
 
+    // the nonce k, x and cx are secrets, and are not logged
     //let mut k = random::rand_big(&mut big_rng);
-    let k = random::rand_scalar_from_rng(rng);
-    debug!("k: {}", k);
-    let A = G.mul(&k);
+    let k = SecretScalar::new(random::rand_scalar_from_rng(rng));
+    let A = key.G_table.mul(&k);
     debug!("A: {}", A);
     let B = M.mul(&k);
     debug!("B: {}", B);
@@ -87,91 +245,294 @@ pub fn dleq<R: Rng>(
     let c = converters::big_from_bytes(&c_hash);
     debug!("c: {}", c);
     debug!("curve_order: {}", curve_order_big);
-    let cx = types::curve::big::BIG::modmul(&c, x, &curve_order_big);
-    debug!("x: {}", x);
-    debug!("cx: {}", cx);
+    let cx = SecretScalar::new(types::curve::big::BIG::modmul(&c, x, &curve_order_big));
 
-    let mut s = types::curve::big::BIG::new_copy(&k);
-    s.sub(&cx);
+    // s = k - cx mod n, without branching on the secret values
+    let s = ct::sub_mod(&k, &cx, &curve_order_big);
     debug!("s: {}", s);
-    if s.cmp(&zero) == Ordering::Less {
-        s.add(&curve_order_big);
-        s.norm();
-    }
 
     debug!("calling verify_dleq_proof");
-    let verify_result = !client::verify_dleq_proof(&c, &s, Z, M, Y, G).is_err();
+    let verify_result = !key.verify_dleq_proof(&c, &s, Z, M).is_err();
     debug!("verify: {}", verify_result);
 
     (c, s)
 }
 
-pub fn check_mac(x: &types::curve::big::BIG, t: &[u8], request_binding: &[u8], observed_info: &[u8]) -> Result<(), Box<Error>> {
-    let T = hashes::hash_to_curve(&t).unwrap();
+pub fn check_mac(x: &types::curve::big::BIG, h2c: hashes::H2cMethod, t: &[u8], request_binding: &[u8], observed_info: &[u8]) -> Result<()> {
+    let T = hashes::hash_to_curve_with(h2c, &t)?;
     let N = T.mul(x);
     let sk = hashes::hash_for_redemption(t, &N);
     let request_binding_calc = hashes::hash_for_request_binding(&sk, observed_info);
-    if request_binding == request_binding_calc.as_slice() {
+    if ct::bytes_eq(request_binding, &request_binding_calc) {
         return Ok(());
     } else {
-        return Err(format!("request_binding and request_binding_calc are different: {:x?} != {:x?}", request_binding, request_binding_calc).into());
+        // the calculated binding is valid for this request, so it isn't logged
+        debug!("request_binding is different from the calculated one: {:x?}", request_binding);
+        return Err(Error::InvalidMac);
     }
 }
 
-pub struct ServerProcessor<'a> {
-    pub secret_key: types::curve::big::BIG,
+// G is hashed from random bytes so that nobody knows its discrete log
+pub fn generate_commitment<R: Rng>(x: &types::curve::big::BIG, rng: &mut R) -> Result<(types::curve::ecp::ECP, types::curve::ecp::ECP)> {
+    let seed = random::new_rand_vec(32, rng);
+    let G = hashes::hash_to_curve(&seed)?;
+    let H = G.mul(x);
+
+    Ok((G, H))
+}
+
+fn decode_request(request_wrapper: &types::ClientRequestWrapper) -> Result<types::ClientRequest> {
+    let request : types::ClientRequest = serde_json::from_slice(&base64::decode(&request_wrapper.bl_sig_req)?)?;
+    debug!("request type: {}", request.type_f);
+
+    Ok(request)
+}
+
+pub struct IssuerKey {
+    pub id: String,
+    pub secret_key: SecretScalar,
     pub G: types::curve::ecp::ECP,
     pub Y: types::curve::ecp::ECP,
-    pub dal: &'a mut db::DAL,
+    // how tokens signed by this key are hashed to the curve
+    pub h2c: hashes::H2cMethod,
 }
 
-impl<'a> ServerProcessor<'a> {
-    pub fn new(secret_key_bytes: &[u8], g_bytes: &[u8], dal: &'a mut db::DAL) -> Result<Self, Box<Error>> {
-        let x = converters::big_from_bytes(secret_key_bytes);
+impl IssuerKey {
+    pub fn new(secret_key_bytes: &[u8], g_bytes: &[u8], h_bytes: &[u8], h2c: hashes::H2cMethod) -> Result<Self> {
+        let x = SecretScalar::new(converters::big_from_bytes(secret_key_bytes));
         let g = ecc::ecp_from_bytes(g_bytes)?;
-        let processor = ServerProcessor {
+        let h = ecc::ecp_from_bytes(h_bytes)?;
+        let Y = g.mul(&x);
+        if Y != h {
+            return Err(Error::CommitmentMismatch);
+        }
+
+        let key = IssuerKey {
+            id: hashes::hash_key_id(&Y),
             secret_key: x,
             G: g,
-            Y: g.mul(&x),
+            Y: Y,
+            h2c: h2c,
+        };
+
+        Ok(key)
+    }
+}
+
+// key of the RFC 9578 type 0x0001 issuer, kept apart from the legacy issuer keys
+pub struct TokenIssuerKey {
+    // hex of token_key_id, under which spent tokens are recorded
+    pub id: String,
+    pub secret_key: SecretScalar<<VoprfGroup as Group>::Scalar>,
+    pub public_key: <VoprfGroup as Group>::Element,
+    pub token_key_id: [u8; token::KEY_ID_SIZE],
+}
+
+impl TokenIssuerKey {
+    // the key is big-endian, and may be shorter than a scalar when it comes from an openssl EC
+    // key, which drops leading zeros
+    pub fn new(secret_key_bytes: &[u8]) -> Result<Self> {
+        if secret_key_bytes.len() > VoprfGroup::NS {
+            return Err(Error::InvalidScalar);
+        }
+        let mut padded = Zeroizing::new(Vec::with_capacity(VoprfGroup::NS));
+        padded.resize(VoprfGroup::NS - secret_key_bytes.len(), 0);
+        padded.extend_from_slice(secret_key_bytes);
+        let secret_key = SecretScalar::new(VoprfGroup::deserialize_scalar(&padded)?);
+        if VoprfGroup::scalar_is_zero(&secret_key) {
+            return Err(Error::InvalidScalar);
+        }
+        let public_key = VoprfGroup::mul(&VoprfGroup::generator(), &secret_key);
+        let token_key_id = token::token_key_id(&VoprfGroup::serialize_element(&public_key));
+
+        let key = TokenIssuerKey {
+            id: hex::encode(&token_key_id),
+            secret_key: secret_key,
+            public_key: public_key,
+            token_key_id: token_key_id,
+        };
+
+        Ok(key)
+    }
+}
+
+// key of the RFC 9578 type 0x0002 issuer, whose tokens anyone with the public key can verify
+pub struct PublicTokenIssuerKey {
+    // hex of token_key_id, under which spent tokens are recorded
+    pub id: String,
+    // openssl clears the private components when the key is freed
+    pub secret_key: Rsa<Private>,
+    // the public key as published in the issuer directory
+    pub public_key_spki: Vec<u8>,
+    pub token_key_id: [u8; token::KEY_ID_SIZE],
+}
+
+impl PublicTokenIssuerKey {
+    pub fn new(secret_key: Rsa<Private>) -> Result<Self> {
+        if blind_rsa::modulus_len(&secret_key) != token::BLIND_RSA_KEY_SIZE {
+            return Err(Error::InvalidInput("type 0x0002 keys must be 2048 bit rsa keys".to_string()));
+        }
+        let public_key_spki = blind_rsa::public_key_to_spki(&secret_key)?;
+        let token_key_id = token::token_key_id(&public_key_spki);
+
+        let key = PublicTokenIssuerKey {
+            id: hex::encode(&token_key_id),
+            secret_key: secret_key,
+            public_key_spki: public_key_spki,
+            token_key_id: token_key_id,
+        };
+
+        Ok(key)
+    }
+}
+
+static NEXT_ISSUE_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // the handle of each connection thread to the issue pool it last used, so requests don't
+    // contend for the shared one
+    static ISSUE_POOL_HANDLE: RefCell<Option<(usize, ThreadPool)>> = RefCell::new(None);
+}
+
+// workers signing the points of issue requests, shared by all of them so concurrent requests
+// queue for the workers instead of each starting their own
+struct IssuePool {
+    id: usize,
+    // ThreadPool isn't Sync, so threads clone their own handle from it once
+    pool: Mutex<ThreadPool>,
+}
+
+impl IssuePool {
+    fn new(workers: usize) -> Self {
+        IssuePool {
+            id: NEXT_ISSUE_POOL_ID.fetch_add(1, Ordering::SeqCst),
+            pool: Mutex::new(ThreadPool::with_name("issue".to_string(), workers)),
+        }
+    }
+
+    fn with_handle<T, F: FnOnce(&ThreadPool) -> T>(&self, f: F) -> T {
+        ISSUE_POOL_HANDLE.with(|handle| {
+            let mut handle = handle.borrow_mut();
+            let current = match *handle {
+                Some((id, _)) => id == self.id,
+                None => false,
+            };
+            if !current {
+                // the pool stays usable if a thread panicked while cloning it
+                let pool = self.pool.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                *handle = Some((self.id, pool));
+            }
+            f(&handle.as_ref().unwrap().1)
+        })
+    }
+}
+
+pub struct ServerProcessor {
+    // the first key issues tokens, the rest only redeem tokens issued before a rotation
+    pub keys: Vec<IssuerKey>,
+    pub max_tokens: usize,
+    pub dal: Arc<db::DAL>,
+    // set to also issue and redeem RFC 9578 tokens of type 0x0001 and 0x0002
+    pub token_key: Option<TokenIssuerKey>,
+    pub public_token_key: Option<PublicTokenIssuerKey>,
+    // None signs on the calling thread
+    issue_pool: Option<IssuePool>,
+    // fixed-base tables of the issuing key's G and Y, for the proofs
+    issuer_tables: client::DleqVerifier,
+}
+
+impl ServerProcessor {
+    pub fn new(keys: Vec<IssuerKey>, max_tokens: u8, dal: Arc<db::DAL>) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(Error::NoIssuingKey);
+        }
+
+        let issuer_tables = client::DleqVerifier::new(&keys[0].G, &keys[0].Y);
+        let processor = ServerProcessor {
+            keys: keys,
+            max_tokens: max_tokens as usize,
             dal: dal,
+            token_key: None,
+            public_token_key: None,
+            issue_pool: None,
+            issuer_tables: issuer_tables,
         };
 
         Ok(processor)
     }
 
-    pub fn process_server_message<R: Rng>(&mut self, buf: &[u8], rng: &mut R) -> Result<String, Box<Error>> {
+    // splits the points of large issue requests across workers threads, see sign_blinded_tokens
+    pub fn set_issue_workers(&mut self, workers: usize) {
+        self.issue_pool = if workers > 1 {
+            Some(IssuePool::new(workers))
+        } else {
+            None
+        };
+    }
+
+    pub fn issuing_key(&self) -> &IssuerKey {
+        &self.keys[0]
+    }
+
+    fn key_by_id(&self, id: &str) -> Result<&IssuerKey> {
+        self.keys.iter()
+            .find(|k| k.id == id)
+            .ok_or_else(|| Error::UnknownKey(id.to_string()))
+    }
+
+    pub fn process_server_message<R: Rng>(&self, buf: &[u8], rng: &mut R) -> Result<String> {
         let request_wrapper : types::ClientRequestWrapper = serde_json::from_slice(&buf)?;
-        println!("bl_sig_req: {:?}", request_wrapper.bl_sig_req);
-        let request : types::ClientRequest = serde_json::from_slice(&base64::decode(&request_wrapper.bl_sig_req)?)?;
-        println!("request type: {}", request.type_f);
+        let request = decode_request(&request_wrapper)?;
 
         match request.type_f.as_ref() {
             "Issue" => self.process_issue(&request, rng),
             "Redeem" => self.process_redeem(&request, &request_wrapper.host, &request_wrapper.http),
-            x => return Err(format!("unknown request: {}", x).into())
+            x => return Err(Error::UnknownRequest(x.to_string()))
+        }
+    }
+
+    // redemption received in the challenge-bypass-token header of an HTTP request. failures are
+    // returned as errors, which the HTTP endpoint answers with a status code
+    pub fn process_redeem_header(&self, header_value: &str, host: &str, path: &str) -> Result<String> {
+        let request_wrapper = http::wrap_header_redemption(header_value, host, path);
+        let request = decode_request(&request_wrapper)?;
+
+        match request.type_f.as_ref() {
+            "Redeem" => {
+                self.redeem_legacy_token(&request, &request_wrapper.host, &request_wrapper.http)?;
+                Ok(types::RedeemStatus::Success.name().to_string())
+            },
+            x => return Err(Error::UnknownRequest(x.to_string()))
         }
     }
 
-    fn process_issue<R: Rng>(&self, request: &types::ClientRequest, rng: &mut R) -> Result<String, Box<Error>> {
-        let mut Ms = vec![];
-        let mut Zs = vec![];
-        for m_str in request.contents.iter() {
-            let M = types::curve::ecp::ECP::frombytes(&base64::decode(m_str)?);
-            Ms.push(M);
-            Zs.push(M.mul(&self.secret_key));
+    fn process_issue<R: Rng>(&self, request: &types::ClientRequest, rng: &mut R) -> Result<String> {
+        if request.contents.is_empty() {
+            return Err(Error::EmptyRequest);
         }
+        if request.contents.len() > self.max_tokens {
+            return Err(Error::TooManyTokens { requested: request.contents.len(), max: self.max_tokens });
+        }
+
+        let key = self.issuing_key();
+        // the rng is only used by the proof, after all points are signed
+        let (Ms, Zs) = match self.issue_pool {
+            Some(ref pool) => pool.with_handle(|pool| sign_blinded_tokens(&key.secret_key, &request.contents, Some(pool)))?,
+            None => sign_blinded_tokens(&key.secret_key, &request.contents, None)?,
+        };
 
-        let (c, s) = batch_dleq(&self.secret_key, &Zs, &Ms, &self.Y, &self.G, rng);
-        println!("c, s: {:?}, {:?}", c, s);
+        let (c, s) = batch_dleq(&key.secret_key, &Zs, &Ms, &self.issuer_tables, rng);
 
         let mut proof_struct : HashMap<String, String> = HashMap::new();
         proof_struct.insert("R".to_string(), base64::encode(&converters::big_to_bytes(&s, 32)));
         proof_struct.insert("C".to_string(), base64::encode(&converters::big_to_bytes(&c, 32)));
 
+        // legacy clients only read P, so the key id goes alongside it
         let mut batch_proof_struct : HashMap<String, String> = HashMap::new();
         batch_proof_struct.insert("P".to_string(), base64::encode(&serde_json::to_vec(&proof_struct)?));
+        batch_proof_struct.insert(types::BATCH_PROOF_KEY_ID.to_string(), key.id.clone());
 
-        let batch_proof_elem = base64::encode(&format!("batch-proof={}", serde_json::to_string(&batch_proof_struct)?).as_bytes());
+        let batch_proof_elem = base64::encode(&format!("{}{}", types::BATCH_PROOF_PREFIX, serde_json::to_string(&batch_proof_struct)?).as_bytes());
 
         let mut resp_arr = vec![];
         for z in Zs {
@@ -187,21 +548,116 @@ impl<'a> ServerProcessor<'a> {
 
     }
 
-    fn process_redeem(&mut self, request: &types::ClientRequest, host: &str, path: &str) -> Result<String, Box<Error>> {
+    // answers with a RedeemResponse. requests that can't be parsed are still errors
+    fn process_redeem(&self, request: &types::ClientRequest, host: &str, path: &str) -> Result<String> {
+        let response = match self.redeem_legacy_token(request, host, path) {
+            Ok(()) => types::RedeemResponse::success(),
+            Err(e) => match redeem_status(&e) {
+                Some(status) => types::RedeemResponse {
+                    status: status,
+                    message: e.to_string(),
+                },
+                None => return Err(e),
+            },
+        };
+
+        Ok(serde_json::to_string(&response)?)
+    }
+
+    fn redeem_legacy_token(&self, request: &types::ClientRequest, host: &str, path: &str) -> Result<()> {
+        if request.contents.len() < 2 {
+            return Err(Error::MalformedRequest(format!("redeem expects token and binding, got {} elements", request.contents.len())));
+        }
         let token = base64::decode(&request.contents[0])?;
         let request_binding = base64::decode(&request.contents[1])?;
 
         let shared_info = mac::build_shared_info(host, path);
-        check_mac(&self.secret_key, &token, &request_binding, &shared_info)?;
+        let key = if request.contents.len() > 2 {
+            let key_id = String::from_utf8(base64::decode(&request.contents[2])?)?;
+            let key = self.key_by_id(&key_id)?;
+            check_mac(&key.secret_key, key.h2c, &token, &request_binding, &shared_info)?;
+            key
+        } else {
+            // legacy redemptions don't name their key, so accept a match with any of them
+            self.keys.iter()
+                .find(|key| check_mac(&key.secret_key, key.h2c, &token, &request_binding, &shared_info).is_ok())
+                .ok_or(Error::InvalidMac)?
+        };
+
+        self.dal.store_spent(&key.id, &token)?;
+
+        Ok(())
+    }
+
+    // answers a binary TokenRequest with a binary TokenResponse
+    pub fn process_token_request<R: Rng>(&self, body: &[u8], rng: &mut R) -> Result<Vec<u8>> {
+        let request = token::TokenRequest::from_bytes(body)?;
+        match request.token_type {
+            token::TOKEN_TYPE_VOPRF => self.process_voprf_token_request(&request, rng),
+            token::TOKEN_TYPE_BLIND_RSA => self.process_blind_rsa_token_request(&request),
+            token_type => Err(Error::UnsupportedTokenType(token_type)),
+        }
+    }
+
+    fn process_voprf_token_request<R: Rng>(&self, request: &token::TokenRequest, rng: &mut R) -> Result<Vec<u8>> {
+        let key = self.token_key.as_ref().ok_or(Error::NoIssuingKey)?;
+        if request.truncated_token_key_id != token::truncate_key_id(&key.token_key_id) {
+            return Err(Error::UnknownKey(format!("{:02x}", request.truncated_token_key_id)));
+        }
+
+        let blinded_element = VoprfGroup::deserialize_element(&request.blinded_msg)?;
+        let (evaluated_elements, proof) = voprf::blind_evaluate::<VoprfGroup, _>(&key.secret_key, &key.public_key, &[blinded_element], rng)?;
+        let response = token::TokenResponse {
+            evaluate_msg: VoprfGroup::serialize_element(&evaluated_elements[0]),
+            evaluate_proof: proof.to_bytes(),
+        };
+
+        Ok(response.to_bytes())
+    }
+
+    fn process_blind_rsa_token_request(&self, request: &token::TokenRequest) -> Result<Vec<u8>> {
+        let key = self.public_token_key.as_ref().ok_or(Error::NoIssuingKey)?;
+        if request.truncated_token_key_id != token::truncate_key_id(&key.token_key_id) {
+            return Err(Error::UnknownKey(format!("{:02x}", request.truncated_token_key_id)));
+        }
+
+        blind_rsa::blind_sign(&key.secret_key, &request.blinded_msg)
+    }
 
-        self.dal.store_spent(&token)?;
+    // checks the authenticator of a token and records it as spent.
+    // the challenge is up to the origin, see token::Token::check_challenge
+    pub fn redeem_token(&self, token: &token::Token) -> Result<()> {
+        match token.token_type {
+            token::TOKEN_TYPE_VOPRF => self.redeem_voprf_token(token),
+            token::TOKEN_TYPE_BLIND_RSA => {
+                let key = self.public_token_key.as_ref().ok_or(Error::NoIssuingKey)?;
+                token::verify_public_token(&key.secret_key, token)?;
+                self.dal.store_spent(&key.id, &token.token_input())
+            },
+            token_type => Err(Error::UnsupportedTokenType(token_type)),
+        }
+    }
+
+    fn redeem_voprf_token(&self, token: &token::Token) -> Result<()> {
+        let key = self.token_key.as_ref().ok_or(Error::NoIssuingKey)?;
+        if token.token_key_id != key.token_key_id {
+            return Err(Error::UnknownKey(hex::encode(&token.token_key_id)));
+        }
 
-        Ok("success".into())
+        let token_input = token.token_input();
+        if !ct::bytes_eq(&voprf::evaluate::<VoprfGroup>(&key.secret_key, &token_input)?, &token.authenticator) {
+            return Err(Error::InvalidToken);
+        }
+
+        self.dal.store_spent(&key.id, &token_input)
     }
 }
 
-mod test {
-    /*
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use rand::{SeedableRng, rngs::SmallRng};
+
     pub fn generator() -> types::curve::ecp::ECP {
         let gx_bytes : &[u8] = &[44, 132, 52, 73, 132, 117, 108, 247, 90, 43, 242, 238, 44, 23, 21, 194, 217, 211, 19, 150, 236, 240, 158, 216, 27, 104, 48, 62, 172, 96, 26, 148];
         let gy_bytes : &[u8] = &[43, 227, 193, 101, 167, 121, 122, 231, 53, 180, 255, 94, 145, 199, 82, 15, 28, 74, 222, 40, 224, 30, 196, 53, 62, 164, 34, 155, 133, 106, 12, 106];
@@ -212,16 +668,59 @@ mod test {
 
         g
     }
-    */
 
+    pub(crate) fn temp_dal(name: &str) -> db::DAL {
+        let mut path = std::env::temp_dir();
+        path.push(format!("privacypass-rs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        db::DAL::new(path.to_str().unwrap()).unwrap()
+    }
+
+    const SEED: [u8; 16] = [1,2,3,4, 5,6,7,8, 9,10,11,12, 13,14,15,16];
+
+    fn test_rng() -> SmallRng {
+        SmallRng::from_seed(SEED)
+    }
+
+    // the fixture of the processor tests: a single key under the test generator, spent tokens
+    // in a fresh db named after the test, and the rng the key was drawn from
+    pub(crate) fn setup(name: &str, h2c: hashes::H2cMethod) -> (ServerProcessor, types::curve::big::BIG, SmallRng) {
+        let mut rng = test_rng();
+        let x_bytes = random::new_rand_vec(32, &mut rng);
+        let x = converters::big_from_bytes(&x_bytes);
+        let G = generator();
+        let g_bytes = ecc::ecp_to_bytes(&G, false);
+        let h_bytes = ecc::ecp_to_bytes(&G.mul(&x), false);
+
+        let key = IssuerKey::new(&x_bytes, &g_bytes, &h_bytes, h2c).unwrap();
+        let dal = Arc::new(temp_dal(name));
+        (ServerProcessor::new(vec![key], 5, dal).unwrap(), x, rng)
+    }
+
+    fn redeem_status_of(processor: &ServerProcessor, request: &types::ClientRequestWrapper) -> types::RedeemStatus {
+        let msg = serde_json::to_vec(request).unwrap();
+        let response = processor.process_server_message(&msg, &mut rand::thread_rng()).unwrap();
+        client::parse_redeem_response(response.as_bytes()).unwrap().status
+    }
 
+    fn wrap_request(type_f: &str, contents: Vec<String>) -> Vec<u8> {
+        let req = types::ClientRequest {
+            type_f: type_f.to_string(),
+            contents: contents,
+        };
+        let wrapped_req = types::ClientRequestWrapper {
+            bl_sig_req: base64::encode(&serde_json::to_string(&req).unwrap()),
+            host: "example.com".to_string(),
+            http: "/".to_string(),
+        };
+        serde_json::to_vec(&wrapped_req).unwrap()
+    }
 
     #[test]
     fn test_sign_blinded_token() {
-        let seed = [1,2,3,4, 5,6,7,8, 9,10,11,12, 13,14,15,16];
-        let mut rng = SmallRng::from_seed(seed);
+        let mut rng = test_rng();
 
-        let (t, r, M) = client::generate_and_blind_token(&mut rng);
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
 
         let mut big_rng = random::new_rand(&mut rng);
         let x = random::rand_big(&mut big_rng);
@@ -238,10 +737,9 @@ mod test {
 
     #[test]
     fn test_server() {
-        let seed = [1,2,3,4, 5,6,7,8, 9,10,11,12, 13,14,15,16];
-        let mut rng = SmallRng::from_seed(seed);
+        let mut rng = test_rng();
 
-        let (t, r, M) = client::generate_and_blind_token(&mut rng);
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
 
         let mut big_rng = random::new_rand(&mut rng);
         //let x = random::rand_big(&mut big_rng);
@@ -257,7 +755,7 @@ mod test {
         let (c, s) = dleq(
             &x,
             &Z, &M,
-            &Y, &G,
+            &client::DleqVerifier::new(&G, &Y),
             &mut rng
         );
 
@@ -267,6 +765,410 @@ mod test {
         let shared_info: [u8; 5] = [0; 5];
         let request_binding = client::mac(&shared_info, &t, &N);
 
-        check_mac(&x, &t, &request_binding, &shared_info).unwrap();
+        check_mac(&x, hashes::H2cMethod::Increment, &t, &request_binding, &shared_info).unwrap();
+    }
+
+    #[test]
+    fn test_process_garbage_messages() {
+        let (processor, _, mut rng) = setup("garbage", hashes::H2cMethod::Increment);
+
+        let mut messages = vec![];
+        messages.push(vec![]);
+        messages.push(b"{}".to_vec());
+        messages.push(b"{\"bl_sig_req\": \"!!!\", \"host\": \"\", \"http\": \"\"}".to_vec());
+        messages.push(wrap_request("Unknown", vec![]));
+        messages.push(wrap_request("Redeem", vec![]));
+        messages.push(wrap_request("Redeem", vec![base64::encode(b"token")]));
+        messages.push(wrap_request("Redeem", vec!["***".to_string(), "***".to_string()]));
+        messages.push(wrap_request("Redeem", vec![base64::encode(b"token"), base64::encode(b"binding")]));
+        messages.push(wrap_request("Issue", vec!["***".to_string()]));
+        messages.push(wrap_request("Issue", vec![base64::encode(&[0])]));
+        messages.push(wrap_request("Issue", vec![base64::encode(&vec![0; types::curve::big::MODBYTES + 1])]));
+        messages.push(wrap_request("Issue", vec![base64::encode(&vec![0x04; 2*types::curve::big::MODBYTES + 1])]));
+        for _ in 0..100 {
+            let len = rng.gen_range(0, 200);
+            messages.push(random::new_rand_vec(len, &mut rng));
+
+            // a random x coordinate may well be on the curve, so only check this doesn't panic
+            let len = rng.gen_range(0, 2*types::curve::big::MODBYTES + 2);
+            let mut point_bytes = random::new_rand_vec(len, &mut rng);
+            if len > 0 {
+                point_bytes[0] = [0x02, 0x03, 0x04][rng.gen_range(0, 3)];
+            }
+            let msg = wrap_request("Issue", vec![base64::encode(&point_bytes)]);
+            let _ = processor.process_server_message(&msg, &mut rng);
+
+            let num_contents = rng.gen_range(0, 4);
+            let contents = (0..num_contents).map(|_| {
+                let len = rng.gen_range(0, 64);
+                base64::encode(&random::new_rand_vec(len, &mut rng))
+            }).collect();
+            messages.push(wrap_request("Redeem", contents));
+        }
+
+        // redemptions that parse are answered with a failed status instead
+        for msg in messages.iter() {
+            match processor.process_server_message(msg, &mut rng) {
+                Err(_) => {},
+                Ok(response) => {
+                    let response : types::RedeemResponse = serde_json::from_str(&response).unwrap();
+                    assert!(response.status != types::RedeemStatus::Success);
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_process_issue_max_tokens() {
+        let (processor, _, mut rng) = setup("max-tokens", hashes::H2cMethod::Increment);
+
+        let (request, _) = client::prepare_issue_request(6, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+        assert!(matches!(processor.process_server_message(&msg, &mut rng), Err(Error::TooManyTokens { requested: 6, max: 5 })));
+
+        let (request, _) = client::prepare_issue_request(0, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+        assert!(matches!(processor.process_server_message(&msg, &mut rng), Err(Error::EmptyRequest)));
+
+        let (request, _) = client::prepare_issue_request(5, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+        processor.process_server_message(&msg, &mut rng).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_issue() {
+        let (mut processor, x, mut rng) = setup("parallel-issue", hashes::H2cMethod::Increment);
+        processor.max_tokens = 100;
+
+        let (request, tokens) = client::prepare_issue_request(100, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+
+        let mut responses = vec![];
+        for workers in [1, 3, 8].iter() {
+            processor.set_issue_workers(*workers);
+            let mut proof_rng = test_rng();
+            responses.push(processor.process_server_message(&msg, &mut proof_rng).unwrap());
+        }
+        assert!(responses[0] == responses[1]);
+        assert!(responses[0] == responses[2]);
+
+        let contents : Vec<String> = tokens.iter()
+            .map(|t| base64::encode(&ecc::ecp_to_bytes(&t.2, true)))
+            .collect();
+        let x = SecretScalar::new(x);
+        let (Ms, Zs) = sign_blinded_tokens(&x, &contents, Some(&ThreadPool::new(8))).unwrap();
+        for i in 0..tokens.len() {
+            assert!(Ms[i] == tokens[i].2);
+            assert!(Zs[i] == sign_blinded_token(&x, &tokens[i].2));
+        }
+    }
+
+    #[test]
+    fn test_issue_worker_failure() {
+        let pool = ThreadPool::new(3);
+        let results = run_chunks(&pool, vec![1, 2, 3], |i: usize| i * 2).unwrap();
+        assert!(results == vec![2, 4, 6]);
+
+        let result = run_chunks(&pool, vec![1, 2, 3], |i: usize| {
+            if i == 2 {
+                panic!("worker failure");
+            }
+            i
+        });
+        assert!(matches!(result, Err(Error::IssueWorkerFailed)));
+
+        // the pool replaces the failed worker
+        assert!(run_chunks(&pool, vec![1, 2, 3], |i: usize| i).unwrap() == vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_concurrent_double_spend() {
+        let (processor, x, mut rng) = setup("double-spend", hashes::H2cMethod::Increment);
+        let processor = Arc::new(processor);
+
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let N = client::unblind_signature(&sign_blinded_token(&x, &M), &r);
+        let request = client::prepare_redeem_request(&t, &N, None, "example.com", "/").unwrap();
+        let msg = Arc::new(serde_json::to_vec(&request).unwrap());
+
+        let handles : Vec<_> = (0..8).map(|_| {
+            let processor = processor.clone();
+            let msg = msg.clone();
+            std::thread::spawn(move || {
+                let response = processor.process_server_message(&msg, &mut rand::thread_rng()).unwrap();
+                client::parse_redeem_response(response.as_bytes()).unwrap().status
+            })
+        }).collect();
+        let statuses : Vec<types::RedeemStatus> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert!(statuses.iter().filter(|s| **s == types::RedeemStatus::Success).count() == 1);
+        assert!(statuses.iter().filter(|s| **s == types::RedeemStatus::DoubleSpend).count() == 7);
+    }
+
+    #[test]
+    fn test_issue_response_counts() {
+        let (processor, _, mut rng) = setup("response-counts", hashes::H2cMethod::Increment);
+        let key_id = processor.issuing_key().id.clone();
+
+        let (request, _) = client::prepare_issue_request(3, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+        let response = processor.process_server_message(&msg, &mut rng).unwrap();
+        let resp : Vec<String> = serde_json::from_slice(&base64::decode(&response).unwrap()).unwrap();
+        let encode = |resp: &[String]| base64::encode(&serde_json::to_string(resp).unwrap());
+
+        let (points, _, _) = client::parse_issue_response(response.as_bytes(), 3, &key_id).unwrap();
+        assert!(points.len() == 3);
+
+        let mut fewer = resp.clone();
+        fewer.remove(0);
+        assert!(matches!(client::parse_issue_response(encode(&fewer).as_bytes(), 3, &key_id), Err(Error::WrongTokenCount { requested: 3, received: 2 })));
+
+        let mut more = resp.clone();
+        more.insert(0, resp[0].clone());
+        assert!(matches!(client::parse_issue_response(encode(&more).as_bytes(), 3, &key_id), Err(Error::WrongTokenCount { requested: 3, received: 4 })));
+
+        // legacy servers don't send the key id
+        let batch_proof_elem = base64::decode(&resp[3]).unwrap();
+        let mut batch_proof_struct : HashMap<String, String> = serde_json::from_slice(&batch_proof_elem[types::BATCH_PROOF_PREFIX.len()..]).unwrap();
+        batch_proof_struct.remove(types::BATCH_PROOF_KEY_ID).unwrap();
+        let mut legacy = resp.clone();
+        legacy[3] = base64::encode(&format!("{}{}", types::BATCH_PROOF_PREFIX, serde_json::to_string(&batch_proof_struct).unwrap()));
+        client::parse_issue_response(encode(&legacy).as_bytes(), 3, &key_id).unwrap();
+
+        assert!(client::parse_issue_response(encode(&resp[..3]).as_bytes(), 3, &key_id).is_err());
+        assert!(client::parse_issue_response(encode(&[]).as_bytes(), 3, &key_id).is_err());
+        assert!(matches!(client::parse_issue_response(response.as_bytes(), 3, "other key"), Err(Error::UnknownKey(_))));
+    }
+
+    // parses an issue response as clients from before key ids do: every element but the last
+    // is a signed point, and only P is read from the batch proof
+    #[test]
+    fn test_legacy_issue_response() {
+        let (processor, x, mut rng) = setup("legacy-issue", hashes::H2cMethod::Increment);
+        let G = generator();
+        let Y = G.mul(&x);
+
+        let (request, tokens) = client::prepare_issue_request(3, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+        let response = processor.process_server_message(&msg, &mut rng).unwrap();
+        let resp : Vec<String> = serde_json::from_slice(&base64::decode(&response).unwrap()).unwrap();
+        assert!(resp.len() == tokens.len() + 1);
+
+        let mut signed_blinded_tokens = vec![];
+        for elem in resp[..resp.len() - 1].iter() {
+            signed_blinded_tokens.push(ecc::ecp_from_bytes(&base64::decode(elem).unwrap()).unwrap());
+        }
+
+        let batch_proof_elem = base64::decode(&resp[resp.len() - 1]).unwrap();
+        let batch_proof_struct : HashMap<String, String> = serde_json::from_slice(&batch_proof_elem["batch-proof=".len()..]).unwrap();
+        let proof_struct : HashMap<String, String> = serde_json::from_slice(&base64::decode(&batch_proof_struct["P"]).unwrap()).unwrap();
+        let s = converters::big_from_bytes(&base64::decode(&proof_struct["R"]).unwrap());
+        let c = converters::big_from_bytes(&base64::decode(&proof_struct["C"]).unwrap());
+
+        client::process_issue_response(&tokens, &signed_blinded_tokens, &G, &Y, &s, &c).unwrap();
+    }
+
+    #[test]
+    fn test_issue_response_wrong_count() {
+        let (processor, x, mut rng) = setup("wrong-count", hashes::H2cMethod::Increment);
+        let key_id = processor.issuing_key().id.clone();
+        let G = generator();
+        let Y = G.mul(&x);
+        let verifier = client::DleqVerifier::new(&G, &Y);
+
+        let (request, tokens) = client::prepare_issue_request(3, hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let msg = serde_json::to_vec(&request).unwrap();
+        let response = processor.process_server_message(&msg, &mut rng).unwrap();
+        let (points, s, c) = client::parse_issue_response(response.as_bytes(), 3, &key_id).unwrap();
+
+        let mut long = points.clone();
+        long.push(points[0]);
+        for signed_blinded_tokens in [&points[..2], &long[..]].iter() {
+            assert!(matches!(client::process_issue_response(&tokens, signed_blinded_tokens, &G, &Y, &s, &c), Err(Error::WrongTokenCount { requested: 3, .. })));
+            assert!(matches!(verifier.process_issue_response(&tokens, signed_blinded_tokens, &s, &c), Err(Error::WrongTokenCount { requested: 3, .. })));
+        }
+        assert!(verifier.process_issue_response(&tokens, &points, &s, &c).unwrap().len() == 3);
+    }
+
+    #[test]
+    fn test_process_redeem_header() {
+        let (processor, x, mut rng) = setup("redeem-header", hashes::H2cMethod::Increment);
+
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let N = client::unblind_signature(&sign_blinded_token(&x, &M), &r);
+        let request = client::prepare_redeem_request(&t, &N, None, "example.com", "/index.html").unwrap();
+
+        assert!(processor.process_redeem_header(&request.bl_sig_req, "example.com", "/other.html").is_err());
+        processor.process_redeem_header(&request.bl_sig_req, "example.com", "/index.html").unwrap();
+        assert!(matches!(processor.process_redeem_header(&request.bl_sig_req, "example.com", "/index.html"), Err(Error::DoubleSpend)));
+    }
+
+    #[test]
+    fn test_commitment_mismatch() {
+        let mut rng = test_rng();
+        let x_bytes = random::new_rand_vec(32, &mut rng);
+        let x = converters::big_from_bytes(&x_bytes);
+        let (G, H) = generate_commitment(&x, &mut rng).unwrap();
+        let g_bytes = ecc::ecp_to_bytes(&G, false);
+        let h_bytes = ecc::ecp_to_bytes(&H, false);
+
+        IssuerKey::new(&x_bytes, &g_bytes, &h_bytes, hashes::H2cMethod::Increment).unwrap();
+
+        let other_h_bytes = ecc::ecp_to_bytes(&generator(), false);
+        assert!(matches!(IssuerKey::new(&x_bytes, &g_bytes, &other_h_bytes, hashes::H2cMethod::Increment), Err(Error::CommitmentMismatch)));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let mut rng = test_rng();
+        let mut keys = vec![];
+        let mut xs = vec![];
+        for _ in 0..2 {
+            let x_bytes = random::new_rand_vec(32, &mut rng);
+            let x = converters::big_from_bytes(&x_bytes);
+            let (G, H) = generate_commitment(&x, &mut rng).unwrap();
+            keys.push(IssuerKey::new(&x_bytes, &ecc::ecp_to_bytes(&G, false), &ecc::ecp_to_bytes(&H, false), hashes::H2cMethod::Increment).unwrap());
+            xs.push(x);
+        }
+        let old_key_id = keys[1].id.clone();
+        let processor = ServerProcessor::new(keys, 5, Arc::new(temp_dal("rotation"))).unwrap();
+
+        // a token issued under the retired key is still redeemable, with or without its key id
+        for with_key_id in [true, false].iter() {
+            let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+            let N = client::unblind_signature(&sign_blinded_token(&xs[1], &M), &r);
+            let key_id = if *with_key_id { Some(old_key_id.as_str()) } else { None };
+            let request = client::prepare_redeem_request(&t, &N, key_id, "example.com", "/").unwrap();
+            assert!(redeem_status_of(&processor, &request) == types::RedeemStatus::Success);
+        }
+
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let N = client::unblind_signature(&sign_blinded_token(&xs[1], &M), &r);
+        let request = client::prepare_redeem_request(&t, &N, Some("unknown"), "example.com", "/").unwrap();
+        assert!(redeem_status_of(&processor, &request) == types::RedeemStatus::UnknownKey);
+    }
+
+    #[test]
+    fn test_swu_key() {
+        let (processor, x, mut rng) = setup("swu", hashes::H2cMethod::Swu);
+
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Swu, &mut rng).unwrap();
+        let N = client::unblind_signature(&sign_blinded_token(&x, &M), &r);
+        let request = client::prepare_redeem_request(&t, &N, None, "example.com", "/").unwrap();
+        assert!(redeem_status_of(&processor, &request) == types::RedeemStatus::Success);
+
+        // a token hashed with the other method doesn't verify under this key
+        let (t, r, M) = client::generate_and_blind_token(hashes::H2cMethod::Increment, &mut rng).unwrap();
+        let N = client::unblind_signature(&sign_blinded_token(&x, &M), &r);
+        let request = client::prepare_redeem_request(&t, &N, None, "example.com", "/").unwrap();
+        assert!(redeem_status_of(&processor, &request) == types::RedeemStatus::BadBinding);
+    }
+
+    #[test]
+    fn test_rfc9578_tokens() {
+        let (mut processor, _, mut rng) = setup("rfc9578", hashes::H2cMethod::Increment);
+
+        let challenge = token::TokenChallenge {
+            token_type: token::TOKEN_TYPE_VOPRF,
+            issuer_name: "issuer.example".to_string(),
+            redemption_context: vec![],
+            origin_info: "origin.example".to_string(),
+        };
+        // the key of the RFC 9497 P384-SHA384 test vectors
+        let (sk, _) = voprf::derive_key_pair::<VoprfGroup>(&[0xa3; 32], b"test key").unwrap();
+        let token_key = TokenIssuerKey::new(&VoprfGroup::serialize_scalar(&sk)).unwrap();
+        let public_key = token_key.public_key;
+
+        let (request, pending) = client::prepare_token_request(&challenge, &public_key, &mut rng).unwrap();
+        let request_bytes = request.to_bytes().unwrap();
+        assert!(matches!(processor.process_token_request(&request_bytes, &mut rng), Err(Error::NoIssuingKey)));
+        processor.token_key = Some(token_key);
+
+        let response = processor.process_token_request(&request_bytes, &mut rng).unwrap();
+        let token = client::finalize_token(&pending, &response, &public_key).unwrap();
+        token.check_challenge(&challenge).unwrap();
+
+        let mut forged = token.clone();
+        forged.nonce[0] ^= 1;
+        assert!(matches!(processor.redeem_token(&forged), Err(Error::InvalidToken)));
+
+        let token = token::Token::from_bytes(&token.to_bytes()).unwrap();
+        processor.redeem_token(&token).unwrap();
+        assert!(matches!(processor.redeem_token(&token), Err(Error::DoubleSpend)));
+
+        // a response from another key fails the proof. its truncated id, 0x6a, differs from the
+        // issuer's 0x01
+        let (other_sk, _) = voprf::derive_key_pair::<VoprfGroup>(&[0xa3; 32], b"other key").unwrap();
+        let other_key = TokenIssuerKey::new(&VoprfGroup::serialize_scalar(&other_sk)).unwrap();
+        let (request, pending) = client::prepare_token_request(&challenge, &other_key.public_key, &mut rng).unwrap();
+        assert!(request.truncated_token_key_id == 0x6a);
+        assert!(token::truncate_key_id(&token.token_key_id) == 0x01);
+        assert!(matches!(processor.process_token_request(&request.to_bytes().unwrap(), &mut rng), Err(Error::UnknownKey(_))));
+        let mut request = request;
+        request.truncated_token_key_id = token::truncate_key_id(&token.token_key_id);
+        let response = processor.process_token_request(&request.to_bytes().unwrap(), &mut rng).unwrap();
+        assert!(client::finalize_token(&pending, &response, &other_key.public_key).is_err());
+    }
+
+    #[test]
+    fn test_rfc9578_tokens_vector() {
+        let (mut processor, _, mut rng) = setup("rfc9578-vector", hashes::H2cMethod::Increment);
+        let (sk, pk) = voprf::derive_key_pair::<VoprfGroup>(&[0xa3; 32], b"test key").unwrap();
+        let token_key = TokenIssuerKey::new(&VoprfGroup::serialize_scalar(&sk)).unwrap();
+        let truncated_token_key_id = token::truncate_key_id(&token_key.token_key_id);
+        processor.token_key = Some(token_key);
+
+        // the first P384-SHA384 vector of RFC 9497 appendix A.4.2, input 0x00
+        let input : &[u8] = &[0x00];
+        let blind = VoprfGroup::deserialize_scalar(&hex::decode("504650f53df8f16f6861633388936ea23338fa65ec36e0290022b48eb562889d89dbfa691d1cde91517fa222ed7ad364").unwrap()).unwrap();
+        let blinded_element = hex::decode("02d338c05cbecb82de13d6700f09cb61190543a7b7e2c6cd4fca56887e564ea82653b27fdad383995ea6d02cf26d0e24d9").unwrap();
+        let evaluation_element = hex::decode("02a7bba589b3e8672aa19e8fd258de2e6aae20101c8d761246de97a6b5ee9cf105febce4327a326255a3c604f63f600ef6").unwrap();
+        let output = hex::decode("3333230886b562ffb8329a8be08fea8025755372817ec969d114d1203d026b4a622beab60220bf19078bca35a529b35c").unwrap();
+
+        let request = token::TokenRequest {
+            token_type: token::TOKEN_TYPE_VOPRF,
+            truncated_token_key_id: truncated_token_key_id,
+            blinded_msg: blinded_element.clone(),
+        };
+        let response = processor.process_token_request(&request.to_bytes().unwrap(), &mut rng).unwrap();
+        let response = token::TokenResponse::from_bytes(&response).unwrap();
+        assert!(response.evaluate_msg == evaluation_element);
+
+        // the proof nonce is random, so check the proof instead of comparing it
+        let proof = voprf::Proof::<VoprfGroup>::from_bytes(&response.evaluate_proof).unwrap();
+        let blinded_element = VoprfGroup::deserialize_element(&blinded_element).unwrap();
+        let evaluated_element = VoprfGroup::deserialize_element(&response.evaluate_msg).unwrap();
+        let outputs = voprf::finalize::<VoprfGroup>(&[input], &[blind], &[evaluated_element], &[blinded_element], &pk, &proof).unwrap();
+        assert!(outputs[0] == output);
+        assert!(voprf::evaluate::<VoprfGroup>(&sk, input).unwrap() == output);
+    }
+
+    #[test]
+    fn test_public_tokens() {
+        let (mut processor, _, mut rng) = setup("public-tokens", hashes::H2cMethod::Increment);
+        let key = PublicTokenIssuerKey::new(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key = blind_rsa::public_key_from_spki(&key.public_key_spki).unwrap();
+        processor.public_token_key = Some(key);
+
+        let challenge = token::TokenChallenge {
+            token_type: token::TOKEN_TYPE_BLIND_RSA,
+            issuer_name: "issuer.example".to_string(),
+            redemption_context: vec![1; 32],
+            origin_info: String::new(),
+        };
+        let (request, pending) = client::prepare_public_token_request(&challenge, &public_key, &mut rng).unwrap();
+        let response = processor.process_token_request(&request.to_bytes().unwrap(), &mut rng).unwrap();
+        let token = client::finalize_public_token(&pending, &response, &public_key).unwrap();
+
+        // origins only need the public key
+        token::verify_public_token(&public_key, &token).unwrap();
+        token.check_challenge(&challenge).unwrap();
+        let mut forged = token.clone();
+        forged.challenge_digest[0] ^= 1;
+        assert!(token::verify_public_token(&public_key, &forged).is_err());
+
+        processor.redeem_token(&token).unwrap();
+        assert!(matches!(processor.redeem_token(&token), Err(Error::DoubleSpend)));
+
+        assert!(PublicTokenIssuerKey::new(Rsa::generate(1024).unwrap()).is_err());
     }
 }
@@ -0,0 +1,378 @@
+// wire structures of Privacy Pass tokens, as specified in RFC 9577 (challenges and tokens)
+// and RFC 9578 (issuance protocols).
+//
+// type 0x0001 uses the P384-SHA384 VOPRF of RFC 9497 (see VoprfGroup), and type 0x0002 uses
+// 2048 bit RSA keys (see blind_rsa).
+
+use amcl::hash256::HASH256;
+use super::{blind_rsa, voprf};
+use super::group::{Group, P384};
+use super::error::{Error, Result};
+
+use openssl::pkey::HasPublic;
+use openssl::rsa::RsaRef;
+
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+pub const TOKEN_TYPE_VOPRF: u16 = 0x0001;
+pub const TOKEN_TYPE_BLIND_RSA: u16 = 0x0002;
+
+// the VOPRF group of type 0x0001 tokens
+pub type VoprfGroup = P384;
+
+pub const NONCE_SIZE: usize = 32;
+pub const DIGEST_SIZE: usize = 32;
+pub const KEY_ID_SIZE: usize = 32;
+// Nh of the VOPRF suite
+pub const VOPRF_AUTHENTICATOR_SIZE: usize = 48;
+// Nk, the modulus size of type 0x0002 keys
+pub const BLIND_RSA_KEY_SIZE: usize = 256;
+
+// media types of the issuance protocol messages
+pub const TOKEN_REQUEST_CONTENT_TYPE: &str = "application/private-token-request";
+pub const TOKEN_RESPONSE_CONTENT_TYPE: &str = "application/private-token-response";
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut sh = HASH256::new();
+    sh.process_array(data);
+    sh.hash()
+}
+
+// token_key_id is the digest of the serialized public key, and requests carry its last byte
+pub fn token_key_id(public_key_bytes: &[u8]) -> [u8; KEY_ID_SIZE] {
+    sha256(public_key_bytes)
+}
+
+pub fn truncate_key_id(token_key_id: &[u8; KEY_ID_SIZE]) -> u8 {
+    token_key_id[KEY_ID_SIZE - 1]
+}
+
+// size of the blinded and signed messages, and of the authenticator, of each token type
+fn blinded_msg_size(token_type: u16) -> Result<usize> {
+    match token_type {
+        TOKEN_TYPE_VOPRF => Ok(VoprfGroup::NE),
+        TOKEN_TYPE_BLIND_RSA => Ok(BLIND_RSA_KEY_SIZE),
+        _ => Err(Error::UnsupportedTokenType(token_type)),
+    }
+}
+
+fn authenticator_size(token_type: u16) -> Result<usize> {
+    match token_type {
+        TOKEN_TYPE_VOPRF => Ok(VOPRF_AUTHENTICATOR_SIZE),
+        TOKEN_TYPE_BLIND_RSA => Ok(BLIND_RSA_KEY_SIZE),
+        _ => Err(Error::UnsupportedTokenType(token_type)),
+    }
+}
+
+fn read_bytes(cursor: &mut Cursor<&[u8]>, len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    cursor.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_array(cursor: &mut Cursor<&[u8]>) -> Result<[u8; 32]> {
+    let mut bytes = [0; 32];
+    cursor.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn check_consumed(cursor: &Cursor<&[u8]>, what: &str) -> Result<()> {
+    if cursor.position() as usize != cursor.get_ref().len() {
+        return Err(Error::MalformedRequest(format!("trailing bytes after {}", what)));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenChallenge {
+    pub token_type: u16,
+    pub issuer_name: String,
+    // empty, or 32 bytes chosen by the origin
+    pub redemption_context: Vec<u8>,
+    // comma separated origin names, possibly empty
+    pub origin_info: String,
+}
+
+impl TokenChallenge {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.issuer_name.is_empty() || self.issuer_name.len() > 0xffff || self.origin_info.len() > 0xffff {
+            return Err(Error::MalformedRequest("challenge field lengths out of range".to_string()));
+        }
+        if self.redemption_context.len() != 0 && self.redemption_context.len() != 32 {
+            return Err(Error::MalformedRequest("redemption context must be empty or 32 bytes".to_string()));
+        }
+
+        let mut bytes = vec![];
+        bytes.write_u16::<BigEndian>(self.token_type)?;
+        bytes.write_u16::<BigEndian>(self.issuer_name.len() as u16)?;
+        bytes.extend_from_slice(self.issuer_name.as_bytes());
+        bytes.push(self.redemption_context.len() as u8);
+        bytes.extend_from_slice(&self.redemption_context);
+        bytes.write_u16::<BigEndian>(self.origin_info.len() as u16)?;
+        bytes.extend_from_slice(self.origin_info.as_bytes());
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let token_type = cursor.read_u16::<BigEndian>()?;
+        let issuer_name_len = cursor.read_u16::<BigEndian>()? as usize;
+        let issuer_name = String::from_utf8(read_bytes(&mut cursor, issuer_name_len)?)?;
+        let redemption_context_len = cursor.read_u8()? as usize;
+        let redemption_context = read_bytes(&mut cursor, redemption_context_len)?;
+        let origin_info_len = cursor.read_u16::<BigEndian>()? as usize;
+        let origin_info = String::from_utf8(read_bytes(&mut cursor, origin_info_len)?)?;
+        check_consumed(&cursor, "challenge")?;
+
+        let challenge = TokenChallenge {
+            token_type: token_type,
+            issuer_name: issuer_name,
+            redemption_context: redemption_context,
+            origin_info: origin_info,
+        };
+        // rejects empty issuer names and bad redemption context lengths
+        challenge.to_bytes()?;
+
+        Ok(challenge)
+    }
+
+    pub fn digest(&self) -> Result<[u8; DIGEST_SIZE]> {
+        Ok(sha256(&self.to_bytes()?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenRequest {
+    pub token_type: u16,
+    pub truncated_token_key_id: u8,
+    pub blinded_msg: Vec<u8>,
+}
+
+impl TokenRequest {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.blinded_msg.len() != blinded_msg_size(self.token_type)? {
+            return Err(Error::MalformedRequest(format!("blinded message of {} bytes", self.blinded_msg.len())));
+        }
+
+        let mut bytes = vec![];
+        bytes.write_u16::<BigEndian>(self.token_type)?;
+        bytes.push(self.truncated_token_key_id);
+        bytes.extend_from_slice(&self.blinded_msg);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let token_type = cursor.read_u16::<BigEndian>()?;
+        let truncated_token_key_id = cursor.read_u8()?;
+        let blinded_msg = read_bytes(&mut cursor, blinded_msg_size(token_type)?)?;
+        check_consumed(&cursor, "token request")?;
+
+        let request = TokenRequest {
+            token_type: token_type,
+            truncated_token_key_id: truncated_token_key_id,
+            blinded_msg: blinded_msg,
+        };
+
+        Ok(request)
+    }
+}
+
+// the issuer's answer to a type 0x0001 request
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenResponse {
+    pub evaluate_msg: Vec<u8>,
+    pub evaluate_proof: Vec<u8>,
+}
+
+impl TokenResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.evaluate_msg.clone();
+        bytes.extend_from_slice(&self.evaluate_proof);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != VoprfGroup::NE + voprf::proof_size::<VoprfGroup>() {
+            return Err(Error::MalformedRequest(format!("token response of {} bytes", bytes.len())));
+        }
+
+        let response = TokenResponse {
+            evaluate_msg: bytes[..VoprfGroup::NE].to_vec(),
+            evaluate_proof: bytes[VoprfGroup::NE..].to_vec(),
+        };
+
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub token_type: u16,
+    pub nonce: [u8; NONCE_SIZE],
+    pub challenge_digest: [u8; DIGEST_SIZE],
+    pub token_key_id: [u8; KEY_ID_SIZE],
+    pub authenticator: Vec<u8>,
+}
+
+impl Token {
+    // the message the issuer evaluates or signs: every field but the authenticator
+    pub fn token_input(&self) -> Vec<u8> {
+        token_input(self.token_type, &self.nonce, &self.challenge_digest, &self.token_key_id)
+    }
+
+    // origins check that a token answers the challenge they sent
+    pub fn check_challenge(&self, challenge: &TokenChallenge) -> Result<()> {
+        if self.token_type != challenge.token_type || self.challenge_digest != challenge.digest()? {
+            return Err(Error::InvalidToken);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.token_input();
+        bytes.extend_from_slice(&self.authenticator);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(bytes);
+        let token_type = cursor.read_u16::<BigEndian>()?;
+        let nonce = read_array(&mut cursor)?;
+        let challenge_digest = read_array(&mut cursor)?;
+        let token_key_id = read_array(&mut cursor)?;
+        let authenticator = read_bytes(&mut cursor, authenticator_size(token_type)?)?;
+        check_consumed(&cursor, "token")?;
+
+        let token = Token {
+            token_type: token_type,
+            nonce: nonce,
+            challenge_digest: challenge_digest,
+            token_key_id: token_key_id,
+            authenticator: authenticator,
+        };
+
+        Ok(token)
+    }
+}
+
+pub fn token_input(
+    token_type: u16, nonce: &[u8; NONCE_SIZE],
+    challenge_digest: &[u8; DIGEST_SIZE], token_key_id: &[u8; KEY_ID_SIZE]) -> Vec<u8> {
+
+    let mut input = vec![(token_type >> 8) as u8, token_type as u8];
+    input.extend_from_slice(nonce);
+    input.extend_from_slice(challenge_digest);
+    input.extend_from_slice(token_key_id);
+    input
+}
+
+// checks a type 0x0002 token with nothing but the issuer's public key, so origins can verify
+// tokens without calling back to the issuer. double spending is up to the caller.
+pub fn verify_public_token<T: HasPublic>(public_key: &RsaRef<T>, token: &Token) -> Result<()> {
+    if token.token_type != TOKEN_TYPE_BLIND_RSA {
+        return Err(Error::UnsupportedTokenType(token.token_type));
+    }
+    if token.token_key_id != token_key_id(&blind_rsa::public_key_to_spki(public_key)?) {
+        return Err(Error::UnknownKey(hex::encode(&token.token_key_id)));
+    }
+
+    blind_rsa::verify(public_key, &token.token_input(), &token.authenticator)
+        .map_err(|_| Error::InvalidToken)
+}
+
+// value of a "PrivateToken token=..." authorization header, as in RFC 9577
+pub fn authorization_header(token: &Token) -> String {
+    format!("PrivateToken token={}", base64::encode_config(&token.to_bytes(), base64::URL_SAFE_NO_PAD))
+}
+
+pub fn parse_authorization_header(value: &str) -> Result<Token> {
+    let value = value.trim();
+    let mut parts = value.splitn(2, |c: char| c.is_ascii_whitespace());
+    let params = match (parts.next(), parts.next()) {
+        (Some(scheme), Some(params)) if scheme.eq_ignore_ascii_case("PrivateToken") => params,
+        _ => return Err(Error::MalformedRequest("not a PrivateToken authorization".to_string())),
+    };
+
+    let encoded = params.split(',')
+        .map(|param| param.trim())
+        .find(|param| param.starts_with("token="))
+        .map(|param| param["token=".len()..].trim_matches('"').trim_end_matches('='))
+        .ok_or_else(|| Error::MalformedRequest("missing token parameter".to_string()))?;
+
+    Token::from_bytes(&base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn challenge() -> TokenChallenge {
+        TokenChallenge {
+            token_type: TOKEN_TYPE_VOPRF,
+            issuer_name: "issuer.example".to_string(),
+            redemption_context: vec![7; 32],
+            origin_info: "origin.example,other.example".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_challenge_roundtrip() {
+        let bytes = challenge().to_bytes().unwrap();
+        assert!(TokenChallenge::from_bytes(&bytes).unwrap() == challenge());
+        assert!(TokenChallenge::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut bad_context = challenge();
+        bad_context.redemption_context = vec![7; 16];
+        assert!(bad_context.to_bytes().is_err());
+
+        let mut no_issuer = challenge();
+        no_issuer.issuer_name = String::new();
+        assert!(no_issuer.to_bytes().is_err());
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let token = Token {
+            token_type: TOKEN_TYPE_VOPRF,
+            nonce: [1; NONCE_SIZE],
+            challenge_digest: challenge().digest().unwrap(),
+            token_key_id: token_key_id(b"public key"),
+            authenticator: vec![2; VOPRF_AUTHENTICATOR_SIZE],
+        };
+
+        let bytes = token.to_bytes();
+        assert!(bytes.len() == 2 + NONCE_SIZE + DIGEST_SIZE + KEY_ID_SIZE + VOPRF_AUTHENTICATOR_SIZE);
+        assert!(Token::from_bytes(&bytes).unwrap() == token);
+        assert!(Token::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let header = authorization_header(&token);
+        assert!(parse_authorization_header(&header).unwrap() == token);
+        let quoted = format!("PrivateToken token=\"{}\"", base64::encode_config(&bytes, base64::URL_SAFE));
+        assert!(parse_authorization_header(&quoted).unwrap() == token);
+        assert!(parse_authorization_header("Bearer abc").is_err());
+        let extended = format!("PrivateTokenXYZ token={}", base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD));
+        assert!(parse_authorization_header(&extended).is_err());
+
+        let mut unknown_type = bytes.clone();
+        unknown_type[1] = 0x7f;
+        assert!(matches!(Token::from_bytes(&unknown_type), Err(Error::UnsupportedTokenType(0x007f))));
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        let request = TokenRequest {
+            token_type: TOKEN_TYPE_VOPRF,
+            truncated_token_key_id: 0x42,
+            blinded_msg: vec![3; VoprfGroup::NE],
+        };
+        let bytes = request.to_bytes().unwrap();
+        assert!(TokenRequest::from_bytes(&bytes).unwrap() == request);
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(TokenRequest::from_bytes(&extended).is_err());
+    }
+}
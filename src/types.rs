@@ -1,5 +1,12 @@
+// the curve of the legacy protocol, whose encodings are fixed to P-256. the RFC 9497 VOPRF
+// can also run over the other groups in group.rs
 pub use amcl::nist256 as curve;
 
+// prefix of the batch proof, the last element of an issue response
+pub const BATCH_PROOF_PREFIX: &str = "batch-proof=";
+// field of the batch proof naming the issuing key, next to the P legacy clients read
+pub const BATCH_PROOF_KEY_ID: &str = "key_id";
+
 #[derive(Serialize, Deserialize)]
 pub struct ClientRequestWrapper {
 	pub bl_sig_req: String,
@@ -13,3 +20,54 @@ pub struct ClientRequest {
 	pub type_f: String,
 	pub contents: Vec<String>,
 }
+
+// outcome of a redemption, as answered by the issuer
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedeemStatus {
+	Success,
+	DoubleSpend,
+	BadBinding,
+	UnknownKey,
+	ServerError,
+}
+
+impl RedeemStatus {
+	pub fn name(&self) -> &'static str {
+		match self {
+			RedeemStatus::Success => "success",
+			RedeemStatus::DoubleSpend => "double-spend",
+			RedeemStatus::BadBinding => "bad-binding",
+			RedeemStatus::UnknownKey => "unknown-key",
+			RedeemStatus::ServerError => "server-error",
+		}
+	}
+
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"success" => Some(RedeemStatus::Success),
+			"double-spend" => Some(RedeemStatus::DoubleSpend),
+			"bad-binding" => Some(RedeemStatus::BadBinding),
+			"unknown-key" => Some(RedeemStatus::UnknownKey),
+			"server-error" => Some(RedeemStatus::ServerError),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedeemResponse {
+	pub status: RedeemStatus,
+	// why the redemption failed, empty on success
+	#[serde(default)]
+	pub message: String,
+}
+
+impl RedeemResponse {
+	pub fn success() -> Self {
+		RedeemResponse {
+			status: RedeemStatus::Success,
+			message: String::new(),
+		}
+	}
+}
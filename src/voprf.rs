@@ -0,0 +1,667 @@
+#![allow(non_snake_case)]
+
+// verifiable OPRF (mode 0x01), as specified in RFC 9497, over any of the groups in group.rs.
+// unlike the legacy protocol the proof always covers a whole batch, and inputs are hashed
+// with the group's RFC 9380 suite under a domain separation tag derived from the context string.
+// the group is always given explicitly, e.g. voprf::blind::<P256, _>(input, rng)
+
+use super::group::Group;
+use super::error::{Error, Result};
+
+use rand::Rng;
+
+pub const MODE_VOPRF: u8 = 0x01;
+
+pub fn context_string<G: Group>() -> Vec<u8> {
+    let mut context = b"OPRFV1-".to_vec();
+    context.push(MODE_VOPRF);
+    context.push(b'-');
+    context.extend_from_slice(G::SUITE_ID.as_bytes());
+    context
+}
+
+fn with_context<G: Group>(prefix: &str) -> Vec<u8> {
+    let mut dst = prefix.as_bytes().to_vec();
+    dst.extend(context_string::<G>());
+    dst
+}
+
+pub fn hash_to_group<G: Group>(input: &[u8]) -> Result<G::Element> {
+    G::hash_to_group(input, &with_context::<G>("HashToGroup-"))
+}
+
+pub fn hash_to_scalar<G: Group>(input: &[u8]) -> Result<G::Scalar> {
+    G::hash_to_scalar(input, &with_context::<G>("HashToScalar-"))
+}
+
+pub fn proof_size<G: Group>() -> usize {
+    2*G::NS
+}
+
+// appends I2OSP(len(data), 2) || data
+fn push_with_len(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.push((data.len() >> 8) as u8);
+    buf.push(data.len() as u8);
+    buf.extend_from_slice(data);
+}
+
+fn check_input_len(input: &[u8]) -> Result<()> {
+    if input.len() > 0xffff {
+        return Err(Error::InvalidInput(format!("input too long: {} bytes", input.len())));
+    }
+    Ok(())
+}
+
+pub struct Proof<G: Group> {
+    pub c: G::Scalar,
+    pub s: G::Scalar,
+}
+
+impl<G: Group> Proof<G> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = G::serialize_scalar(&self.c);
+        bytes.extend(G::serialize_scalar(&self.s));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != proof_size::<G>() {
+            return Err(Error::InvalidScalar);
+        }
+        let proof = Proof {
+            c: G::deserialize_scalar(&bytes[..G::NS])?,
+            s: G::deserialize_scalar(&bytes[G::NS..])?,
+        };
+
+        Ok(proof)
+    }
+}
+
+pub fn derive_key_pair<G: Group>(seed: &[u8], info: &[u8]) -> Result<(G::Scalar, G::Element)> {
+    check_input_len(info)?;
+    let mut derive_input = seed.to_vec();
+    push_with_len(&mut derive_input, info);
+    derive_input.push(0);
+    let dst = with_context::<G>("DeriveKeyPair");
+
+    for counter in 0..256 {
+        let last = derive_input.len() - 1;
+        derive_input[last] = counter as u8;
+        let sk = G::hash_to_scalar(&derive_input, &dst)?;
+        if !G::scalar_is_zero(&sk) {
+            let pk = G::mul(&G::generator(), &sk);
+            return Ok((sk, pk));
+        }
+    }
+
+    Err(Error::InvalidInput("can't derive a non-zero key".to_string()))
+}
+
+pub fn generate_key_pair<G: Group, R: Rng>(rng: &mut R) -> (G::Scalar, G::Element) {
+    let sk = G::random_scalar(rng);
+    let pk = G::mul(&G::generator(), &sk);
+    (sk, pk)
+}
+
+// returns the blind and the blinded element to send to the server
+pub fn blind<G: Group, R: Rng>(input: &[u8], rng: &mut R) -> Result<(G::Scalar, G::Element)> {
+    let blind = G::random_scalar(rng);
+    let blinded_element = blind_with_scalar::<G>(input, &blind)?;
+    Ok((blind, blinded_element))
+}
+
+pub fn blind_with_scalar<G: Group>(input: &[u8], blind: &G::Scalar) -> Result<G::Element> {
+    check_input_len(input)?;
+    let input_element = hash_to_group::<G>(input)?;
+    Ok(G::mul(&input_element, blind))
+}
+
+pub fn blind_evaluate<G: Group, R: Rng>(
+    sk: &G::Scalar, pk: &G::Element,
+    blinded_elements: &[G::Element], rng: &mut R) -> Result<(Vec<G::Element>, Proof<G>)> {
+
+    let r = G::random_scalar(rng);
+    blind_evaluate_with_scalar::<G>(sk, pk, blinded_elements, &r)
+}
+
+// r is the proof nonce, only exposed so the RFC test vectors can fix it
+pub fn blind_evaluate_with_scalar<G: Group>(
+    sk: &G::Scalar, pk: &G::Element,
+    blinded_elements: &[G::Element], r: &G::Scalar) -> Result<(Vec<G::Element>, Proof<G>)> {
+
+    if blinded_elements.is_empty() {
+        return Err(Error::EmptyRequest);
+    }
+
+    let evaluated_elements : Vec<G::Element> = blinded_elements.iter()
+        .map(|blinded_element| G::mul(blinded_element, sk))
+        .collect();
+    let proof = generate_proof::<G>(sk, &G::generator(), pk, blinded_elements, &evaluated_elements, r)?;
+
+    Ok((evaluated_elements, proof))
+}
+
+pub fn finalize<G: Group>(
+    inputs: &[&[u8]], blinds: &[G::Scalar],
+    evaluated_elements: &[G::Element], blinded_elements: &[G::Element],
+    pk: &G::Element, proof: &Proof<G>) -> Result<Vec<Vec<u8>>> {
+
+    let count = inputs.len();
+    if count == 0 {
+        return Err(Error::EmptyRequest);
+    }
+    if blinds.len() != count || evaluated_elements.len() != count || blinded_elements.len() != count {
+        return Err(Error::MalformedRequest(format!("expected {} elements in every batch", count)));
+    }
+
+    verify_proof::<G>(&G::generator(), pk, blinded_elements, evaluated_elements, proof)?;
+
+    let mut outputs = Vec::with_capacity(count);
+    for i in 0..count {
+        check_input_len(inputs[i])?;
+        let N = G::mul(&evaluated_elements[i], &G::scalar_invert(&blinds[i]));
+        outputs.push(finalize_hash::<G>(inputs[i], &N));
+    }
+
+    Ok(outputs)
+}
+
+// the unblinded output, computed directly by the server e.g. to check a redeemed token
+pub fn evaluate<G: Group>(sk: &G::Scalar, input: &[u8]) -> Result<Vec<u8>> {
+    check_input_len(input)?;
+    let N = G::mul(&hash_to_group::<G>(input)?, sk);
+    Ok(finalize_hash::<G>(input, &N))
+}
+
+fn finalize_hash<G: Group>(input: &[u8], N: &G::Element) -> Vec<u8> {
+    let mut hash_input = vec![];
+    push_with_len(&mut hash_input, input);
+    push_with_len(&mut hash_input, &G::serialize_element(N));
+    hash_input.extend_from_slice(b"Finalize");
+
+    G::hash(&hash_input)
+}
+
+fn composite_seed<G: Group>(B: &G::Element) -> Vec<u8> {
+    let mut seed_input = vec![];
+    push_with_len(&mut seed_input, &G::serialize_element(B));
+    push_with_len(&mut seed_input, &with_context::<G>("Seed-"));
+
+    G::hash(&seed_input)
+}
+
+fn composite_weight<G: Group>(seed: &[u8], i: usize, C: &G::Element, D: &G::Element) -> Result<G::Scalar> {
+    let mut composite_input = vec![];
+    push_with_len(&mut composite_input, seed);
+    composite_input.push((i >> 8) as u8);
+    composite_input.push(i as u8);
+    push_with_len(&mut composite_input, &G::serialize_element(C));
+    push_with_len(&mut composite_input, &G::serialize_element(D));
+    composite_input.extend_from_slice(b"Composite");
+
+    hash_to_scalar::<G>(&composite_input)
+}
+
+// folds the batch into a single (M, Z) pair with weights derived from the whole batch
+fn compute_composites<G: Group>(B: &G::Element, Cs: &[G::Element], Ds: &[G::Element]) -> Result<(G::Element, G::Element)> {
+    let seed = composite_seed::<G>(B);
+    let mut M = G::identity();
+    let mut Z = G::identity();
+    for i in 0..Cs.len() {
+        let d = composite_weight::<G>(&seed, i, &Cs[i], &Ds[i])?;
+        M = G::add(&M, &G::mul(&Cs[i], &d));
+        Z = G::add(&Z, &G::mul(&Ds[i], &d));
+    }
+
+    Ok((M, Z))
+}
+
+// the prover knows k, so Z = k*M saves a multiplication per element
+fn compute_composites_fast<G: Group>(
+    k: &G::Scalar, B: &G::Element,
+    Cs: &[G::Element], Ds: &[G::Element]) -> Result<(G::Element, G::Element)> {
+
+    let seed = composite_seed::<G>(B);
+    let mut M = G::identity();
+    for i in 0..Cs.len() {
+        let d = composite_weight::<G>(&seed, i, &Cs[i], &Ds[i])?;
+        M = G::add(&M, &G::mul(&Cs[i], &d));
+    }
+    let Z = G::mul(&M, k);
+
+    Ok((M, Z))
+}
+
+fn challenge<G: Group>(B: &G::Element, M: &G::Element, Z: &G::Element, t2: &G::Element, t3: &G::Element) -> Result<G::Scalar> {
+    let mut challenge_input = vec![];
+    for p in [B, M, Z, t2, t3].iter() {
+        push_with_len(&mut challenge_input, &G::serialize_element(p));
+    }
+    challenge_input.extend_from_slice(b"Challenge");
+
+    hash_to_scalar::<G>(&challenge_input)
+}
+
+// proves that log_A(B) == log_Cs[i](Ds[i]) for every i
+pub fn generate_proof<G: Group>(
+    k: &G::Scalar, A: &G::Element, B: &G::Element,
+    Cs: &[G::Element], Ds: &[G::Element], r: &G::Scalar) -> Result<Proof<G>> {
+
+    let (M, Z) = compute_composites_fast::<G>(k, B, Cs, Ds)?;
+    let t2 = G::mul(A, r);
+    let t3 = G::mul(&M, r);
+
+    let c = challenge::<G>(B, &M, &Z, &t2, &t3)?;
+    let s = G::scalar_sub(r, &G::scalar_mul(&c, k));
+
+    Ok(Proof { c: c, s: s })
+}
+
+pub fn verify_proof<G: Group>(
+    A: &G::Element, B: &G::Element,
+    Cs: &[G::Element], Ds: &[G::Element], proof: &Proof<G>) -> Result<()> {
+
+    if Cs.len() != Ds.len() {
+        return Err(Error::InvalidProof);
+    }
+    let (M, Z) = compute_composites::<G>(B, Cs, Ds)?;
+
+    let t2 = G::add(&G::mul(A, &proof.s), &G::mul(B, &proof.c));
+    let t3 = G::add(&G::mul(&M, &proof.s), &G::mul(&Z, &proof.c));
+
+    let expected_c = challenge::<G>(B, &M, &Z, &t2, &t3)?;
+    if !G::scalar_eq(&expected_c, &proof.c) {
+        return Err(Error::InvalidProof);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::group::P256;
+    use crate::types::curve::{big::BIG, ecp::ECP};
+    use rand::{SeedableRng, rngs::SmallRng};
+
+    fn test_key() -> (BIG, ECP) {
+        derive_key_pair::<P256>(&[0xa3; 32], b"test key").unwrap()
+    }
+
+    // RFC 9497, appendix A.3.2
+    #[test]
+    fn test_derive_key_pair() {
+        let (sk, pk) = test_key();
+        assert!(hex::encode(&P256::serialize_scalar(&sk)) == "ca5d94c8807817669a51b196c34c1b7f8442fde4334a7121ae4736364312fca6");
+        assert!(hex::encode(&P256::serialize_element(&pk)) == "03e17e70604bcabe198882c0a1f27a92441e774224ed9c702e51dd17038b102462");
+    }
+
+    fn scalar_of<G: Group>(hex_str: &str) -> G::Scalar {
+        G::deserialize_scalar(&hex::decode(hex_str).unwrap()).unwrap()
+    }
+
+    // runs a vector of RFC 9497 appendix A under the key derived from the RFC's seed, with the
+    // blinds and proof nonce it fixes. batch vectors blind their second input with the nonce of
+    // the single ones
+    fn check_vector<G: Group>(
+        inputs: &[&[u8]], blinds: &[&str], r: &str,
+        blinded: &[&str], evaluated: &[&str], proof: &str, outputs: &[&str]) {
+
+        let (sk, pk) = derive_key_pair::<G>(&[0xa3; 32], b"test key").unwrap();
+        let blinds : Vec<G::Scalar> = blinds.iter().map(|blind| scalar_of::<G>(blind)).collect();
+        let r = scalar_of::<G>(r);
+
+        let mut blinded_elements = vec![];
+        for i in 0..inputs.len() {
+            let blinded_element = blind_with_scalar::<G>(inputs[i], &blinds[i]).unwrap();
+            assert!(hex::encode(&G::serialize_element(&blinded_element)) == blinded[i]);
+            blinded_elements.push(blinded_element);
+        }
+
+        let (evaluated_elements, generated_proof) = blind_evaluate_with_scalar::<G>(&sk, &pk, &blinded_elements, &r).unwrap();
+        for i in 0..inputs.len() {
+            assert!(hex::encode(&G::serialize_element(&evaluated_elements[i])) == evaluated[i]);
+        }
+        assert!(hex::encode(&generated_proof.to_bytes()) == proof);
+
+        let finalized = finalize::<G>(inputs, &blinds, &evaluated_elements, &blinded_elements, &pk, &generated_proof).unwrap();
+        for i in 0..inputs.len() {
+            assert!(hex::encode(&finalized[i]) == outputs[i]);
+            assert!(evaluate::<G>(&sk, inputs[i]).unwrap() == finalized[i]);
+        }
+    }
+
+    // RFC 9497, appendix A.3.2
+    #[test]
+    fn test_vectors() {
+        let blind = "3338fa65ec36e0290022b48eb562889d89dbfa691d1cde91517fa222ed7ad364";
+        let nonce = "f9db001266677f62c095021db018cd8cbb55941d4073698ce45c405d1348b7b1";
+
+        check_vector::<P256>(
+            &[&[0x00]], &[blind], nonce,
+            &["02dd05901038bb31a6fae01828fd8d0e49e35a486b5c5d4b4994013648c01277da"],
+            &["0209f33cab60cf8fe69239b0afbcfcd261af4c1c5632624f2e9ba29b90ae83e4a2"],
+            concat!(
+                "e7c2b3c5c954c035949f1f74e6bce2ed539a3be267d1481e9ddb178533df4c26",
+                "64f69d065c604a4fd953e100b856ad83804eb3845189babfa5a702090d6fc5fa"),
+            &["0412e8f78b02c415ab3a288e228978376f99927767ff37c5718d420010a645a1"]);
+
+        check_vector::<P256>(
+            &[&[0x5a; 17]], &[blind], nonce,
+            &["03cd0f033e791c4d79dfa9c6ed750f2ac009ec46cd4195ca6fd3800d1e9b887dbd"],
+            &["030d2985865c693bf7af47ba4d3a3813176576383d19aff003ef7b0784a0d83cf1"],
+            concat!(
+                "2787d729c57e3d9512d3aa9e8708ad226bc48e0f1750b0767aaff73482c44b8d",
+                "2873d74ec88aebd3504961acea16790a05c542d9fbff4fe269a77510db00abab"),
+            &["771e10dcd6bcd3664e23b8f2a710cfaaa8357747c4a8cbba03133967b5c24f18"]);
+
+        check_vector::<P256>(
+            &[&[0x00], &[0x5a; 17]], &[blind, nonce],
+            "350e8040f828bf6ceca27405420cdf3d63cb3aef005f40ba51943c8026877963",
+            &[
+                "02dd05901038bb31a6fae01828fd8d0e49e35a486b5c5d4b4994013648c01277da",
+                "03462e9ae64cae5b83ba98a6b360d942266389ac369b923eb3d557213b1922f8ab"],
+            &[
+                "0209f33cab60cf8fe69239b0afbcfcd261af4c1c5632624f2e9ba29b90ae83e4a2",
+                "02bb24f4d838414aef052a8f044a6771230ca69c0a5677540fff738dd31bb69771"],
+            concat!(
+                "bdcc351707d02a72ce49511c7db990566d29d6153ad6f8982fad2b435d6ce4d6",
+                "0da1e6b3fa740811bde34dd4fe0aa1b5fe6600d0440c9ddee95ea7fad7a60cf2"),
+            &[
+                "0412e8f78b02c415ab3a288e228978376f99927767ff37c5718d420010a645a1",
+                "771e10dcd6bcd3664e23b8f2a710cfaaa8357747c4a8cbba03133967b5c24f18"]);
+    }
+
+    // RFC 9497, appendix A.4.2
+    #[test]
+    fn test_vectors_p384() {
+        use crate::group::P384;
+
+        let (sk, pk) = derive_key_pair::<P384>(&[0xa3; 32], b"test key").unwrap();
+        assert!(hex::encode(&P384::serialize_scalar(&sk)) == concat!(
+            "051646b9e6e7a71ae27c1e1d0b87b4381db6d3595eeeb1ad",
+            "b41579adbf992f4278f9016eafc944edaa2b43183581779d"));
+        assert!(hex::encode(&P384::serialize_element(&pk)) == concat!(
+            "031d689686c611991b55f1a1d8f4305ccd6cb719446f660a",
+            "30db61b7aa87b46acf59b7c0d4a9077b3da21c25dd482229a0"));
+
+        let blind = concat!(
+            "504650f53df8f16f6861633388936ea23338fa65ec36e029",
+            "0022b48eb562889d89dbfa691d1cde91517fa222ed7ad364");
+        let nonce = concat!(
+            "803d955f0e073a04aa5d92b3fb739f56f9db001266677f62",
+            "c095021db018cd8cbb55941d4073698ce45c405d1348b7b1");
+
+        check_vector::<P384>(
+            &[&[0x00]], &[blind], nonce,
+            &[concat!(
+                "02d338c05cbecb82de13d6700f09cb61190543a7b7e2c6cd",
+                "4fca56887e564ea82653b27fdad383995ea6d02cf26d0e24d9")],
+            &[concat!(
+                "02a7bba589b3e8672aa19e8fd258de2e6aae20101c8d7612",
+                "46de97a6b5ee9cf105febce4327a326255a3c604f63f600ef6")],
+            concat!(
+                "bfc6cf3859127f5fe25548859856d6b7fa1c7459f0ba5712",
+                "a806fc091a3000c42d8ba34ff45f32a52e40533efd2a03bc",
+                "87f3bf4f9f58028297ccb9ccb18ae7182bcd1ef239df77e3",
+                "be65ef147f3acf8bc9cbfc5524b702263414f043e3b7ca2e"),
+            &[concat!(
+                "3333230886b562ffb8329a8be08fea8025755372817ec969",
+                "d114d1203d026b4a622beab60220bf19078bca35a529b35c")]);
+
+        check_vector::<P384>(
+            &[&[0x5a; 17]], &[blind], nonce,
+            &[concat!(
+                "02f27469e059886f221be5f2cca03d2bdc61e55221721c3b",
+                "3e56fc012e36d31ae5f8dc058109591556a6dbd3a8c69c433b")],
+            &[concat!(
+                "03f16f903947035400e96b7f531a38d4a07ac89a80f89d86",
+                "a1bf089c525a92c7f4733729ca30c56ce78b1ab4f7d92db8b4")],
+            concat!(
+                "d005d6daaad7571414c1e0c75f7e57f2113ca9f4604e84bc",
+                "90f9be52da896fff3bee496dcde2a578ae9df315032585f8",
+                "01fb21c6080ac05672b291e575a40295b306d967717b28e0",
+                "8fcc8ad1cab47845d16af73b3e643ddcc191208e71c64630"),
+            &[concat!(
+                "b91c70ea3d4d62ba922eb8a7d03809a441e1c3c7af915cbc",
+                "2226f485213e895942cd0f8580e6d99f82221e66c40d274f")]);
+
+        check_vector::<P384>(
+            &[&[0x00], &[0x5a; 17]], &[blind, nonce],
+            concat!(
+                "a097e722ed2427de86966910acba9f5c350e8040f828bf6c",
+                "eca27405420cdf3d63cb3aef005f40ba51943c8026877963"),
+            &[
+                concat!(
+                    "02d338c05cbecb82de13d6700f09cb61190543a7b7e2c6cd",
+                    "4fca56887e564ea82653b27fdad383995ea6d02cf26d0e24d9"),
+                concat!(
+                    "02fa02470d7f151018b41e82223c32fad824de6ad4b5ce9f",
+                    "8e9f98083c9a726de9a1fc39d7a0cb6f4f188dd9cea01474cd")],
+            &[
+                concat!(
+                    "02a7bba589b3e8672aa19e8fd258de2e6aae20101c8d7612",
+                    "46de97a6b5ee9cf105febce4327a326255a3c604f63f600ef6"),
+                concat!(
+                    "028e9e115625ff4c2f07bf87ce3fd73fc77994a7a0c1df03",
+                    "d2a630a3d845930e2e63a165b114d98fe34e61b68d23c0b50a")],
+            concat!(
+                "6d8dcbd2fc95550a02211fb78afd013933f307d21e7d855b",
+                "0b1ed0af78076d8137ad8b0a1bfa05676d325249c1dbb9a5",
+                "2bd81b1c2b7b0efc77cf7b278e1c947f6283f1d4c513053f",
+                "c0ad19e026fb0c30654b53d9cea4b87b037271b5d2e2d0ea"),
+            &[
+                concat!(
+                    "3333230886b562ffb8329a8be08fea8025755372817ec969",
+                    "d114d1203d026b4a622beab60220bf19078bca35a529b35c"),
+                concat!(
+                    "b91c70ea3d4d62ba922eb8a7d03809a441e1c3c7af915cbc",
+                    "2226f485213e895942cd0f8580e6d99f82221e66c40d274f")]);
+    }
+
+    // RFC 9497, appendix A.5.2
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_vectors_p521() {
+        use crate::group::P521;
+
+        let (sk, pk) = derive_key_pair::<P521>(&[0xa3; 32], b"test key").unwrap();
+        assert!(hex::encode(&P521::serialize_scalar(&sk)) == concat!(
+            "015c7fc1b4a0b1390925bae915bd9f3d72009d44d9241b962428aad5d13f2280",
+            "3311e7102632a39addc61ea440810222715c9d2f61f03ea424ec9ab1fe5e31cf9238"));
+        assert!(hex::encode(&P521::serialize_element(&pk)) == concat!(
+            "0301505d646f6e4c9102451eb39730c4ba1c4087618641edbdba4a60896b07fd",
+            "0c9414ce553cbf25b81dfcca50a8f6724ab7a2bc4d0cf736967a287bb6084cc0678ac0"));
+
+        let blind = concat!(
+            "00d1dccf7a51bafaf75d4a866d53d8cafe4d504650f53df8f16f686163338893",
+            "6ea23338fa65ec36e0290022b48eb562889d89dbfa691d1cde91517fa222ed7ad364");
+        let nonce = concat!(
+            "015e80ae32363b32cb76ad4b95a5a34e46bb803d955f0e073a04aa5d92b3fb73",
+            "9f56f9db001266677f62c095021db018cd8cbb55941d4073698ce45c405d1348b7b1");
+
+        check_vector::<P521>(
+            &[&[0x00]], &[blind], nonce,
+            &[concat!(
+                "0301d6e4fb545e043ddb6aee5d5ceeee1b44102615ab04430c27dd0f56988ded",
+                "cb1df32ef384f160e0e76e718605f14f3f582f9357553d153b996795b4b3628a4f6380")],
+            &[concat!(
+                "03013fdeaf887f3d3d283a79e696a54b66ff0edcb559265e204a958acf840e09",
+                "30cc147e2a6835148d8199eebc26c03e9394c9762a1c991dde40bca0f8ca003eefb045")],
+            concat!(
+                "0077fcc8ec6d059d7759b0a61f871e7c1dadc65333502e09a51994328f79e5bd",
+                "a3357b9a4f410a1760a3612c2f8f27cb7cb032951c047cc66da60da583df7b24",
+                "7edd0188e5eb99c71799af1d80d643af16ffa1545acd9e9233fbb370455b10eb",
+                "257ea12a1667c1b4ee5b0ab7c93d50ae89602006960f083ca9adc4f6276c0ad6",
+                "0440393c"),
+            &[concat!(
+                "5e003d9b2fb540b3d4bab5fedd154912246da1ee5e557afd8f56415faa1a0fad",
+                "ff6517da802ee254437e4f60907b4cda146e7ba19e249eef7be405549f62954b")]);
+
+        check_vector::<P521>(
+            &[&[0x5a; 17]], &[blind], nonce,
+            &[concat!(
+                "03005b05e656cb609ce5ff5faf063bb746d662d67bbd07c062638396f52f0392",
+                "180cf2365cabb0ece8e19048961d35eeae5d5fa872328dce98df076ee154dd191c615e")],
+            &[concat!(
+                "0301b19fcf482b1fff04754e282292ed736c5f0aa080d4f42663cd3a416c6596",
+                "f03129e8e096d8671fe5b0d19838312c511d2ce08d431e43e3ef06199d8cab7426238d")],
+            concat!(
+                "01ec9fece444caa6a57032e8963df0e945286f88fbdf233fb5101f0924f7ea89",
+                "c47023f5f72f240e61991fd33a299b5b38c45a5e2dd1a67b072e59dfe86708a3",
+                "59c701e38d383c60cf6969463bcf13251bedad47b7941f52e409a3591398e279",
+                "24410b18a301c0e19f527cad504fa08388050ac634e1b05c5216d337742f2754",
+                "e1fc502f"),
+            &[concat!(
+                "fa15eebba81ecf40954f7135cb76f69ef22c6bae394d1a4362f9b03066b54b66",
+                "04d39f2e53369ca6762a3d9787e230e832aa85955af40ecb8deebb009a8cf474")]);
+
+        check_vector::<P521>(
+            &[&[0x00], &[0x5a; 17]], &[blind, nonce],
+            concat!(
+                "01ec21c7bb69b0734cb48dfd68433dd93b0fa097e722ed2427de86966910acba",
+                "9f5c350e8040f828bf6ceca27405420cdf3d63cb3aef005f40ba51943c8026877963"),
+            &[
+                concat!(
+                    "0301d6e4fb545e043ddb6aee5d5ceeee1b44102615ab04430c27dd0f56988ded",
+                    "cb1df32ef384f160e0e76e718605f14f3f582f9357553d153b996795b4b3628a4f6380"),
+                concat!(
+                    "0301403b597538b939b450c93586ba275f9711ba07e42364bac1d5769c6824a8",
+                    "b55be6f9a536df46d952b11ab2188363b3d6737635d9543d4dba14a6e19421b9245bf5")],
+            &[
+                concat!(
+                    "03013fdeaf887f3d3d283a79e696a54b66ff0edcb559265e204a958acf840e09",
+                    "30cc147e2a6835148d8199eebc26c03e9394c9762a1c991dde40bca0f8ca003eefb045"),
+                concat!(
+                    "03001f96424497e38c46c904978c2fa1636c5c3dd2e634a85d8a7265977c5dce",
+                    "1f02c7e6c118479f0751767b91a39cce6561998258591b5d7c1bb02445a9e08e4f3e8d")],
+            concat!(
+                "00b4d215c8405e57c7a4b53398caf55f1f1623aaeb22408ddb9ea29130909b3f",
+                "95dbb1ff366e81e86e918f9f2fd8b80dbb344cd498c9499d112905e585417e00",
+                "68c600fe5dea18b389ef6c4cc062935607b8ccbbb9a84fba3143868a3e8a58ef",
+                "a0bf6ca642804d09dc06e980f64837811227c4267b217f1099a4e28b0854f4e5",
+                "ee659796"),
+            &[
+                concat!(
+                    "5e003d9b2fb540b3d4bab5fedd154912246da1ee5e557afd8f56415faa1a0fad",
+                    "ff6517da802ee254437e4f60907b4cda146e7ba19e249eef7be405549f62954b"),
+                concat!(
+                    "fa15eebba81ecf40954f7135cb76f69ef22c6bae394d1a4362f9b03066b54b66",
+                    "04d39f2e53369ca6762a3d9787e230e832aa85955af40ecb8deebb009a8cf474")]);
+    }
+
+    // RFC 9497, appendix A.1.2. ristretto255 scalars are little-endian
+    #[cfg(feature = "ristretto255")]
+    #[test]
+    fn test_vectors_ristretto255() {
+        use crate::group::Ristretto255;
+
+        let blind = "64d37aed22a27f5191de1c1d69fadb899d8862b58eb4220029e036ec4c1f6706";
+        let nonce = "222a5e897cf59db8145db8d16e597e8facb80ae7d4e26d9881aa6f61d645fc0e";
+
+        check_vector::<Ristretto255>(
+            &[&[0x00]], &[blind], nonce,
+            &["863f330cc1a1259ed5a5998a23acfd37fb4351a793a5b3c090b642ddc439b945"],
+            &["aa8fa048764d5623868679402ff6108d2521884fa138cd7f9c7669a9a014267e"],
+            concat!(
+                "ddef93772692e535d1a53903db24367355cc2cc78de93b3be5a8ffcc6985dd06",
+                "6d4346421d17bf5117a2a1ff0fcb2a759f58a539dfbe857a40bce4cf49ec600d"),
+            &[concat!(
+                "b58cfbe118e0cb94d79b5fd6a6dafb98764dff49c14e1770b566e42402da1a7d",
+                "a4d8527693914139caee5bd03903af43a491351d23b430948dd50cde10d32b3c")]);
+
+        check_vector::<Ristretto255>(
+            &[&[0x5a; 17]], &[blind], nonce,
+            &["cc0b2a350101881d8a4cba4c80241d74fb7dcbfde4a61fde2f91443c2bf9ef0c"],
+            &["60a59a57208d48aca71e9e850d22674b611f752bed48b36f7a91b372bd7ad468"],
+            concat!(
+                "401a0da6264f8cf45bb2f5264bc31e109155600babb3cd4e5af7d181a2c9dc0a",
+                "67154fabf031fd936051dec80b0b6ae29c9503493dde7393b722eafdf5a50b02"),
+            &[concat!(
+                "8a9a2f3c7f085b65933594309041fc1898d42d0858e59f90814ae90571a6df60",
+                "356f4610bf816f27afdd84f47719e480906d27ecd994985890e5f539e7ea74b6")]);
+
+        check_vector::<Ristretto255>(
+            &[&[0x00], &[0x5a; 17]], &[blind, nonce],
+            "419c4f4f5052c53c45f3da494d2b67b220d02118e0857cdbcf037f9ea84bbe0c",
+            &[
+                "863f330cc1a1259ed5a5998a23acfd37fb4351a793a5b3c090b642ddc439b945",
+                "90a0145ea9da29254c3a56be4fe185465ebb3bf2a1801f7124bbbadac751e654"],
+            &[
+                "aa8fa048764d5623868679402ff6108d2521884fa138cd7f9c7669a9a014267e",
+                "cc5ac221950a49ceaa73c8db41b82c20372a4c8d63e5dded2db920b7eee36a2a"],
+            concat!(
+                "cc203910175d786927eeb44ea847328047892ddf8590e723c37205cb74600b0a",
+                "5ab5337c8eb4ceae0494c2cf89529dcf94572ed267473d567aeed6ab873dee08"),
+            &[
+                concat!(
+                    "b58cfbe118e0cb94d79b5fd6a6dafb98764dff49c14e1770b566e42402da1a7d",
+                    "a4d8527693914139caee5bd03903af43a491351d23b430948dd50cde10d32b3c"),
+                concat!(
+                    "8a9a2f3c7f085b65933594309041fc1898d42d0858e59f90814ae90571a6df60",
+                    "356f4610bf816f27afdd84f47719e480906d27ecd994985890e5f539e7ea74b6")]);
+    }
+
+    fn check_batch<G: Group>() {
+        let mut rng = SmallRng::from_seed([1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16]);
+        let (sk, pk) = generate_key_pair::<G, _>(&mut rng);
+
+        let inputs : Vec<Vec<u8>> = (0..5).map(|i| vec![i; 32]).collect();
+        let mut blinds = vec![];
+        let mut blinded_elements = vec![];
+        for input in inputs.iter() {
+            let (blind, blinded_element) = blind::<G, _>(input, &mut rng).unwrap();
+            blinds.push(blind);
+            blinded_elements.push(blinded_element);
+        }
+
+        let (evaluated_elements, proof) = blind_evaluate::<G, _>(&sk, &pk, &blinded_elements, &mut rng).unwrap();
+        let input_refs : Vec<&[u8]> = inputs.iter().map(|input| input.as_slice()).collect();
+        let outputs = finalize::<G>(&input_refs, &blinds, &evaluated_elements, &blinded_elements, &pk, &proof).unwrap();
+        for i in 0..inputs.len() {
+            assert!(outputs[i] == evaluate::<G>(&sk, &inputs[i]).unwrap());
+        }
+
+        // a proof doesn't carry over to a reordered batch or another key
+        let mut swapped = evaluated_elements.clone();
+        swapped.swap(0, 1);
+        assert!(verify_proof::<G>(&G::generator(), &pk, &blinded_elements, &swapped, &proof).is_err());
+        let (_, other_pk) = generate_key_pair::<G, _>(&mut rng);
+        assert!(verify_proof::<G>(&G::generator(), &other_pk, &blinded_elements, &evaluated_elements, &proof).is_err());
+
+        let proof = Proof::<G>::from_bytes(&proof.to_bytes()).unwrap();
+        assert!(verify_proof::<G>(&G::generator(), &pk, &blinded_elements, &evaluated_elements, &proof).is_ok());
+    }
+
+    #[test]
+    fn test_batch() {
+        check_batch::<P256>();
+    }
+
+    #[test]
+    fn test_batch_p384() {
+        check_batch::<crate::group::P384>();
+    }
+
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_batch_p521() {
+        check_batch::<crate::group::P521>();
+    }
+
+    #[cfg(feature = "ristretto255")]
+    #[test]
+    fn test_batch_ristretto255() {
+        check_batch::<crate::group::Ristretto255>();
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let (_, pk) = test_key();
+        let bytes = P256::serialize_element(&pk);
+        assert!(P256::deserialize_element(&bytes).unwrap() == pk);
+        assert!(P256::deserialize_element(&crate::ecc::ecp_to_bytes(&pk, false)).is_err());
+        assert!(P256::deserialize_scalar(&[0xff; 32]).is_err());
+        assert!(Proof::<P256>::from_bytes(&[0; 63]).is_err());
+    }
+}